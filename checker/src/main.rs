@@ -1,3 +1,98 @@
+use std::path::Path;
+
+use clap::{App, Arg};
+use common::{
+    config_loader::TwinImageConfig,
+    isl_parser::{apply_isl, instruction_lines},
+    problem::{command_cost_list, similarity_cost, total_cost, CostModel, Image, State},
+    problem_loader::Problem,
+};
+
 fn main() {
-    println!("Hello, world!");
+    let app = App::new("xyzchecker")
+        .version("1")
+        .author("xyz600")
+        .about("offline scorer for icfpc 2022 submissions")
+        .arg(Arg::with_name("solution").help("ISL file to evaluate").required(true).takes_value(true))
+        .arg(
+            Arg::with_name("problem-id")
+                .help("input problem id. target, initial state and cost are loaded from dataset/{id}.*")
+                .short('i')
+                .long("problem-id")
+                .takes_value(true)
+                .required_unless_present("target"),
+        )
+        .arg(Arg::with_name("target").help("target png file").long("target").takes_value(true).conflicts_with("problem-id"))
+        .arg(
+            Arg::with_name("initial-config")
+                .help("initial block config (*.initial.json). used with --target")
+                .long("initial-config")
+                .takes_value(true)
+                .conflicts_with("problem-id"),
+        )
+        .arg(
+            Arg::with_name("initial-image")
+                .help("initial canvas png (*.initial.png). used with --initial-config")
+                .long("initial-image")
+                .takes_value(true)
+                .requires("initial-config"),
+        )
+        .arg(
+            Arg::with_name("cost-model")
+                .help("instruction cost json. default: dataset/{id}.cost.json if exists")
+                .long("cost-model")
+                .takes_value(true),
+        );
+
+    let matches = app.get_matches();
+
+    // 問題番号があれば dataset/ から問題の初期状態ごと読む. なければ指定されたファイルから作る
+    let (image, cost_model, init_state) = if let Some(problem_id) = matches.value_of("problem-id") {
+        let problem = Problem::load(problem_id.parse().unwrap());
+        let init_state = problem.initial_state();
+        (problem.image, problem.cost_model, init_state)
+    } else {
+        let image = Image::new(matches.value_of("target").unwrap());
+        let init_state = if let Some(config_filepath) = matches.value_of("initial-config") {
+            let config = TwinImageConfig::load(Path::new(config_filepath));
+            if let Some(initial_image_filepath) = matches.value_of("initial-image") {
+                State::create_with_initial_image(&config, &Image::new(initial_image_filepath))
+            } else {
+                State::create_with_config(&config)
+            }
+        } else {
+            State::new(image.height, image.width)
+        };
+        (image, CostModel::default(), init_state)
+    };
+    let cost_model = if let Some(cost_model_filepath) = matches.value_of("cost-model") {
+        CostModel::load_from(Path::new(cost_model_filepath))
+    } else {
+        cost_model
+    };
+
+    let solution_filepath = matches.value_of("solution").unwrap();
+    let text = match std::fs::read_to_string(solution_filepath) {
+        Ok(text) => text,
+        Err(err) => {
+            eprintln!("{}: {}", solution_filepath, err);
+            std::process::exit(1);
+        }
+    };
+    let state = match apply_isl(&text, init_state) {
+        Ok(state) => state,
+        Err(err) => {
+            eprintln!("{}: {}", solution_filepath, err);
+            std::process::exit(1);
+        }
+    };
+
+    let cost_list = command_cost_list(&image, &state, &cost_model);
+    for ((line_number, line), cost) in instruction_lines(&text).into_iter().zip(cost_list.iter()) {
+        println!("{:>6} {:>8}  {}", line_number, cost, line.trim());
+    }
+    let command_cost: usize = cost_list.iter().sum();
+    println!("instruction: {}", command_cost);
+    println!("similarity: {}", similarity_cost(&image, &state));
+    println!("total: {}", total_cost(&image, &state, &cost_model));
 }
@@ -40,7 +40,7 @@ pub fn k_means_clustering(data: &Vec<Color8>, k: usize, random: &mut CachedRando
         cluster_size.fill(0);
         for (di, color) in data.iter().enumerate() {
             let mut best_assign = 0;
-            let mut best_score = std::f64::MAX;
+            let mut best_score = f64::MAX;
             for ci in 0..k {
                 let score = (center[ci].to64() - color.to64()).square().horizontal_add();
                 if score < best_score {
@@ -78,7 +78,7 @@ mod tests {
             Color8::new(100, 102, 100, 102),
         ];
         let mut random = CachedRandom::new(65536, 42);
-        let (assign_table, color) = k_means_clustering(&colors, 2, &mut random);
+        let (assign_table, color) = k_means_clustering(&colors, 2, &mut random).unwrap();
 
         assert_eq!(assign_table[0], assign_table[1]);
         assert_eq!(assign_table[0], assign_table[2]);
@@ -1,14 +1,19 @@
 use std::path::Path;
 
-use crate::problem::{Color8, Rectangle};
+use crate::problem::{Color8, Pos, Rectangle};
 use serde::{Deserialize, Serialize};
 
+#[allow(non_snake_case)]
 #[derive(Serialize, Deserialize, Debug)]
 struct RawBlockConfig {
     blockId: String,
     bottomLeft: Vec<usize>,
     topRight: Vec<usize>,
-    color: Vec<u8>,
+    // 初期画像が png の問題では color の代わりに pngBottomLeftPoint がある
+    #[serde(default)]
+    color: Option<Vec<u8>>,
+    #[serde(default)]
+    pngBottomLeftPoint: Option<Vec<usize>>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -22,6 +27,8 @@ pub struct BlockConfig {
     pub id: usize,
     pub rect: Rectangle,
     pub color: Color8,
+    // 初期画像上の位置. State::create_with_initial_image で使う
+    pub texture: Option<Pos>,
 }
 
 pub struct TwinImageConfig {
@@ -32,7 +39,7 @@ pub struct TwinImageConfig {
 
 impl TwinImageConfig {
     pub fn load(filepath: &Path) -> TwinImageConfig {
-        let file = std::fs::File::open(&filepath).unwrap();
+        let file = std::fs::File::open(filepath).unwrap();
         let reader = std::io::BufReader::new(file);
         let deserialized: RawTwinImageConfig = serde_json::from_reader(reader).unwrap();
 
@@ -48,11 +55,13 @@ impl TwinImageConfig {
             let height = block.topRight[0] - block.bottomLeft[0];
             let width = block.topRight[1] - block.bottomLeft[1];
             let rect = Rectangle::new(bottom, left, height, width);
-            let color = Color8::new(block.color[0], block.color[1], block.color[2], block.color[3]);
+            let color = block.color.as_ref().map_or(Color8::default(), |c| Color8::new(c[0], c[1], c[2], c[3]));
+            let texture = block.pngBottomLeftPoint.as_ref().map(|p| Pos::new(p[0], p[1]));
             let block_config = BlockConfig {
                 id: block.blockId.parse().unwrap(),
                 rect,
                 color,
+                texture,
             };
             config.blocks.push(block_config);
         }
@@ -66,11 +75,20 @@ mod tests {
     use std::path::Path;
 
     use super::TwinImageConfig;
+    use crate::problem::Pos;
 
     #[test]
     fn test_config() {
-        let path = Path::new("dataset/26.initial.json");
+        let path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/../dataset/26.initial.json"));
         let config = TwinImageConfig::load(path);
         assert_eq!(config.height, 400);
     }
+
+    #[test]
+    fn test_png_config() {
+        let path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/../dataset/36.initial.json"));
+        let config = TwinImageConfig::load(path);
+        assert_eq!(config.blocks.len(), 1);
+        assert_eq!(config.blocks[0].texture, Some(Pos::new(0, 0)));
+    }
 }
@@ -30,7 +30,7 @@ impl RangeMedianCalculator {
     }
 
     pub fn median(&self, sy: usize, sx: usize, ey: usize, ex: usize) -> u8 {
-        let threashold = (1 + (ey - sy) * (ex - sx)) / 2;
+        let threashold = ((ey - sy) * (ex - sx)).div_ceil(2);
         let mut sum = 0;
         for val in 0..256 {
             let freq = self.buffer[val].range_sum(sy, sx, ey, ex);
@@ -0,0 +1,77 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// solver の打ち切り時刻と、外からの中断要求
+/// clone したものは中断要求を共有する
+#[derive(Clone, Debug)]
+pub struct Deadline {
+    // None なら時間制限なし
+    end: Option<Instant>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Deadline {
+    pub fn new(time_limit: Option<Duration>) -> Deadline {
+        Deadline {
+            end: time_limit.map(|limit| Instant::now() + limit),
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn unlimited() -> Deadline {
+        Deadline::new(None)
+    }
+
+    /// 今から time_limit 後と self の早い方で打ち切る. 中断要求は self と共有する
+    pub fn child(&self, time_limit: Duration) -> Deadline {
+        let end = Instant::now() + time_limit;
+        Deadline {
+            end: Some(self.end.map_or(end, |v| v.min(end))),
+            cancelled: self.cancelled.clone(),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// 時間切れか中断されていたら true
+    /// 重い処理のループの中で呼んで、true なら今までの最良解を返す
+    pub fn is_expired(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed) || self.end.is_some_and(|end| end <= Instant::now())
+    }
+
+    /// 時間制限がなければ None
+    pub fn remaining(&self) -> Option<Duration> {
+        self.end.map(|end| end.saturating_duration_since(Instant::now()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deadline() {
+        let deadline = Deadline::unlimited();
+        assert!(!deadline.is_expired());
+        assert!(deadline.remaining().is_none());
+
+        let child = deadline.child(Duration::from_secs(0));
+        assert!(child.is_expired());
+        assert!(!deadline.is_expired());
+
+        let child = deadline.child(Duration::from_secs(100));
+        assert!(!child.is_expired());
+        deadline.cancel();
+        assert!(child.is_expired());
+
+        let deadline = Deadline::new(Some(Duration::from_secs(100)));
+        assert!(deadline.child(Duration::from_secs(1000)).remaining().unwrap() <= Duration::from_secs(100));
+    }
+}
@@ -0,0 +1,163 @@
+use crate::problem::{command_cost_list, instruction_cost, Command, CostModel, Image, State, ALPHA};
+
+#[derive(Clone)]
+struct History {
+    pixel_cost: f64,
+    command_cost: usize,
+    // (block_idx, 変更前の画素コスト)
+    block_cost_list: Vec<(usize, f64)>,
+}
+
+/// apply / undo のたびに、変化したブロックの画素コストだけを計算し直して評価値を管理する
+/// 生きているブロックが canvas を重なりなく覆っている前提で、evaluate と同じ値を返す
+#[derive(Clone)]
+pub struct IncrementalEvaluator<'a> {
+    image: &'a Image,
+    cost_model: CostModel,
+    // block_list の index ごとの ALPHA を掛ける前の画素コスト
+    block_cost: Vec<f64>,
+    // 生きているブロックの block_cost の和
+    pixel_cost: f64,
+    command_cost: usize,
+    history: Vec<History>,
+}
+
+impl<'a> IncrementalEvaluator<'a> {
+    pub fn new(image: &'a Image, state: &State, cost_model: &CostModel) -> IncrementalEvaluator<'a> {
+        let mut block_cost = vec![0f64; state.block_list.len()];
+        let mut pixel_cost = 0f64;
+        for block_index in 0..state.block_list.len() {
+            if state.block_list[block_index].is_child {
+                block_cost[block_index] = state.block_rmse(image, block_index);
+                pixel_cost += block_cost[block_index];
+            }
+        }
+        IncrementalEvaluator {
+            image,
+            cost_model: *cost_model,
+            block_cost,
+            pixel_cost,
+            command_cost: command_cost_list(image, state, cost_model).iter().sum(),
+            history: vec![],
+        }
+    }
+
+    /// evaluate と同じ値
+    pub fn score(&self) -> f64 {
+        self.similarity_cost() + self.command_cost as f64
+    }
+
+    pub fn similarity_cost(&self) -> f64 {
+        (self.pixel_cost * ALPHA).round()
+    }
+
+    pub fn command_cost(&self) -> usize {
+        self.command_cost
+    }
+
+    fn update_block_cost(&mut self, state: &State, block_index: usize, block_cost_list: &mut Vec<(usize, f64)>) {
+        let cost = state.block_rmse(self.image, block_index);
+        block_cost_list.push((block_index, self.block_cost[block_index]));
+        self.pixel_cost += cost - self.block_cost[block_index];
+        self.block_cost[block_index] = cost;
+    }
+
+    /// state.apply(cmd) をして、評価値を更新する
+    pub fn apply(&mut self, state: &mut State, cmd: Command) {
+        let mut history = History {
+            pixel_cost: self.pixel_cost,
+            command_cost: self.command_cost,
+            block_cost_list: vec![],
+        };
+
+        self.command_cost += instruction_cost(&cmd, state.block_size_for_cost(&cmd), self.image.size(), &self.cost_model);
+
+        let prev_len = state.block_list.len();
+        state.apply(cmd);
+        self.block_cost.resize(state.block_list.len(), 0f64);
+
+        match cmd {
+            Command::HorizontalSplit(block_index, _) | Command::VerticalSplit(block_index, _) | Command::PointSplit(block_index, _) => {
+                self.pixel_cost -= self.block_cost[block_index];
+                for child_index in prev_len..state.block_list.len() {
+                    self.update_block_cost(state, child_index, &mut history.block_cost_list);
+                }
+            }
+            Command::Color(block_index, _) => {
+                self.update_block_cost(state, block_index, &mut history.block_cost_list);
+            }
+            Command::Swap(block_index1, block_index2) => {
+                self.update_block_cost(state, block_index1, &mut history.block_cost_list);
+                self.update_block_cost(state, block_index2, &mut history.block_cost_list);
+            }
+            Command::Merge(block_index1, block_index2) => {
+                // merge しても表示される画素は変わらない
+                history.block_cost_list.push((prev_len, self.block_cost[prev_len]));
+                self.block_cost[prev_len] = self.block_cost[block_index1] + self.block_cost[block_index2];
+            }
+        }
+        self.history.push(history);
+    }
+
+    /// state.undo() をして、評価値を apply 前に戻す
+    /// この evaluator で apply したコマンドだけ戻せる
+    pub fn undo(&mut self, state: &mut State) {
+        let history = self.history.pop().unwrap();
+        state.undo();
+
+        for (block_index, cost) in history.block_cost_list.into_iter().rev() {
+            self.block_cost[block_index] = cost;
+        }
+        self.block_cost.truncate(state.block_list.len());
+        self.pixel_cost = history.pixel_cost;
+        self.command_cost = history.command_cost;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        problem::{evaluate, Color8, Pos},
+        random::CachedRandom,
+    };
+
+    #[test]
+    fn test_incremental_evaluate() {
+        let mut random = CachedRandom::new(65536, 42);
+        let mut buffer = vec![];
+        for _ in 0..40 * 40 {
+            let v = random.next_int_range(0, 255) as u8;
+            buffer.push(Color8::new(v, 255 - v, v / 2, 255));
+        }
+        let image = Image { height: 40, width: 40, buffer };
+
+        let mut state = State::new(40, 40);
+        let mut evaluator = IncrementalEvaluator::new(&image, &state, &CostModel::default());
+        assert_eq!(evaluator.score(), evaluate(&image, &state, &CostModel::default()));
+
+        let cmd_list = [
+            Command::Color(0, Color8::new(100, 100, 100, 255)),
+            Command::PointSplit(0, Pos::new(20, 20)),
+            Command::Color(2, Color8::new(10, 200, 10, 255)),
+            Command::VerticalSplit(3, 30),
+            Command::HorizontalSplit(1, 10),
+            Command::Color(6, Color8::new(0, 0, 0, 0)),
+            Command::Swap(2, 4),
+            Command::Merge(5, 6),
+            Command::Color(9, Color8::new(255, 0, 0, 255)),
+        ];
+
+        let mut state_list = vec![];
+        for cmd in cmd_list.iter() {
+            state_list.push(state.clone());
+            evaluator.apply(&mut state, *cmd);
+            assert_eq!(evaluator.score(), evaluate(&image, &state, &CostModel::default()));
+        }
+        for prev_state in state_list.into_iter().rev() {
+            evaluator.undo(&mut state);
+            assert_eq!(state, prev_state);
+            assert_eq!(evaluator.score(), evaluate(&image, &state, &CostModel::default()));
+        }
+    }
+}
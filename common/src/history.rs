@@ -0,0 +1,71 @@
+use std::{
+    cell::RefCell,
+    process::Command,
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// 解を作った solver の情報. history に一緒に残す
+#[derive(Clone, Default, Debug)]
+pub struct RunInfo {
+    pub solver: String,
+    // 実行時の設定. "kind=blank time_limit=20" など
+    pub params: String,
+}
+
+thread_local! {
+    static CURRENT_RUN: RefCell<RunInfo> = RefCell::new(RunInfo::default());
+}
+
+/// このスレッドで以降に保存される解は run_info の solver が作ったものとして記録する
+/// solver は保存時に自分の名前を知らないので、呼び出し側で設定しておく
+pub fn set_current_run(run_info: RunInfo) {
+    CURRENT_RUN.with(|v| *v.borrow_mut() = run_info);
+}
+
+pub fn current_run() -> RunInfo {
+    CURRENT_RUN.with(|v| v.borrow().clone())
+}
+
+/// history/{id}/index.jsonl の1行. 解そのものは history/{id}/{index}.json
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct HistoryEntry {
+    pub index: usize,
+    // unix time (秒)
+    pub timestamp: u64,
+    pub solver: String,
+    pub params: String,
+    pub git_revision: Option<String>,
+    pub score: f64,
+    // 保存時に最良解を更新したか
+    pub improved: bool,
+}
+
+impl HistoryEntry {
+    pub fn new(index: usize, score: f64, improved: bool, run_info: RunInfo) -> HistoryEntry {
+        HistoryEntry {
+            index,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            solver: run_info.solver,
+            params: run_info.params,
+            git_revision: git_revision(),
+            score,
+            improved,
+        }
+    }
+}
+
+/// 実行中のバイナリを build した working tree の (今の) revision
+/// git がなければ None
+fn git_revision() -> Option<String> {
+    static REVISION: OnceLock<Option<String>> = OnceLock::new();
+    REVISION
+        .get_or_init(|| {
+            let output = Command::new("git").args(["describe", "--always", "--dirty"]).current_dir(env!("CARGO_MANIFEST_DIR")).output().ok()?;
+            if !output.status.success() {
+                return None;
+            }
+            Some(String::from_utf8(output.stdout).ok()?.trim().to_string())
+        })
+        .clone()
+}
@@ -1,6 +1,6 @@
 use crate::random::CachedRandom;
 
-const INVALID: usize = std::usize::MAX;
+const INVALID: usize = usize::MAX;
 
 pub struct IntSet {
     buffer: Vec<usize>,
@@ -0,0 +1,324 @@
+use std::{collections::HashMap, fmt, path::Path};
+
+use crate::problem::{Color8, Command, MoveError, Pos, State};
+
+/// ISL の読み込みに失敗した理由
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    // 失敗した位置. line, column は 1-indexed
+    Syntax { line: usize, column: usize, message: String },
+    // ファイルが読めない
+    Io(String),
+}
+
+impl ParseError {
+    fn new(line: usize, column: usize, message: String) -> ParseError {
+        ParseError::Syntax { line, column, message }
+    }
+
+    /// (line, column). Io なら None
+    pub fn position(&self) -> Option<(usize, usize)> {
+        match *self {
+            ParseError::Syntax { line, column, .. } => Some((line, column)),
+            ParseError::Io(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Syntax { line, column, message } => write!(f, "line {}, column {}: {}", line, column, message),
+            ParseError::Io(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+struct Token<'a> {
+    // [] の場合は中身
+    text: &'a str,
+    column: usize,
+    bracketed: bool,
+}
+
+fn tokenize(line_number: usize, line: &str) -> Result<Vec<Token<'_>>, ParseError> {
+    let bytes = line.as_bytes();
+    let mut ret = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i].is_ascii_whitespace() {
+            i += 1;
+        } else if bytes[i] == b'[' {
+            let start = i;
+            while i < bytes.len() && bytes[i] != b']' {
+                i += 1;
+            }
+            if i == bytes.len() {
+                return Err(ParseError::new(line_number, start + 1, "unclosed '['".to_string()));
+            }
+            ret.push(Token {
+                text: line[start + 1..i].trim(),
+                column: start + 1,
+                bracketed: true,
+            });
+            i += 1;
+        } else {
+            let start = i;
+            while i < bytes.len() && !bytes[i].is_ascii_whitespace() && bytes[i] != b'[' {
+                i += 1;
+            }
+            ret.push(Token {
+                text: &line[start..i],
+                column: start + 1,
+                bracketed: false,
+            });
+        }
+    }
+    Ok(ret)
+}
+
+/// 1行分の ISL を読みながら、block id の解決や引数の検証をする
+struct LineParser<'a, 'b> {
+    line_number: usize,
+    tokens: Vec<Token<'a>>,
+    index_of_id: &'b HashMap<String, usize>,
+}
+
+impl<'a, 'b> LineParser<'a, 'b> {
+    fn error(&self, column: usize, message: String) -> ParseError {
+        ParseError::new(self.line_number, column, message)
+    }
+
+    fn expect_arity(&self, arity: usize) -> Result<(), ParseError> {
+        let name = &self.tokens[0];
+        if self.tokens.len() < arity + 1 {
+            let column = self.tokens.last().map_or(name.column, |t| t.column + t.text.len());
+            return Err(self.error(column, format!("'{}' expects {} arguments, but {} given", name.text, arity, self.tokens.len() - 1)));
+        }
+        if self.tokens.len() > arity + 1 {
+            return Err(self.error(self.tokens[arity + 1].column, format!("unexpected argument for '{}'", name.text)));
+        }
+        for token in self.tokens[1..].iter() {
+            if !token.bracketed {
+                return Err(self.error(token.column, format!("argument must be enclosed in []: '{}'", token.text)));
+            }
+        }
+        Ok(())
+    }
+
+    fn block_index(&self, arg: usize) -> Result<usize, ParseError> {
+        let token = &self.tokens[arg];
+        if let Some(index) = self.index_of_id.get(token.text) {
+            Ok(*index)
+        } else {
+            Err(self.error(token.column, format!("block [{}] does not exist", token.text)))
+        }
+    }
+
+    fn number_list(&self, arg: usize, len: usize) -> Result<Vec<usize>, ParseError> {
+        let token = &self.tokens[arg];
+        let values = token.text.split(',').map(|v| v.trim().parse::<usize>()).collect::<Vec<_>>();
+        if values.len() != len || values.iter().any(|v| v.is_err()) {
+            return Err(self.error(token.column, format!("expected {} non-negative integers, but found [{}]", len, token.text)));
+        }
+        Ok(values.into_iter().map(|v| v.unwrap()).collect())
+    }
+
+    fn parse(&self) -> Result<Command, ParseError> {
+        let name = &self.tokens[0];
+        if name.bracketed {
+            return Err(self.error(name.column, "instruction name is missing".to_string()));
+        }
+        match name.text {
+            "cut" if self.tokens.len() == 4 => {
+                self.expect_arity(3)?;
+                let block_index = self.block_index(1)?;
+                let orientation = &self.tokens[2];
+                let offset = self.number_list(3, 1)?[0];
+                match orientation.text {
+                    "x" | "X" => Ok(Command::VerticalSplit(block_index, offset)),
+                    "y" | "Y" => Ok(Command::HorizontalSplit(block_index, offset)),
+                    _ => Err(self.error(orientation.column, format!("unknown orientation [{}]", orientation.text))),
+                }
+            }
+            "cut" => {
+                self.expect_arity(2)?;
+                let block_index = self.block_index(1)?;
+                let values = self.number_list(2, 2)?;
+                Ok(Command::PointSplit(block_index, Pos::new(values[1], values[0])))
+            }
+            "color" => {
+                self.expect_arity(2)?;
+                let block_index = self.block_index(1)?;
+                let token = &self.tokens[2];
+                let values = token.text.split(',').map(|v| v.trim().parse::<u8>()).collect::<Vec<_>>();
+                if values.len() != 4 || values.iter().any(|v| v.is_err()) {
+                    return Err(self.error(token.column, format!("expected rgba color in 0..=255, but found [{}]", token.text)));
+                }
+                let values = values.into_iter().map(|v| v.unwrap()).collect::<Vec<_>>();
+                Ok(Command::Color(block_index, Color8::new(values[0], values[1], values[2], values[3])))
+            }
+            "swap" => {
+                self.expect_arity(2)?;
+                Ok(Command::Swap(self.block_index(1)?, self.block_index(2)?))
+            }
+            "merge" => {
+                self.expect_arity(2)?;
+                Ok(Command::Merge(self.block_index(1)?, self.block_index(2)?))
+            }
+            _ => Err(self.error(name.column, format!("unknown instruction '{}'", name.text))),
+        }
+    }
+
+    /// State::check で弾かれた理由を ISL 上の位置に対応付ける
+    fn move_error(&self, err: MoveError) -> ParseError {
+        let last = self.tokens.last().unwrap();
+        match err {
+            MoveError::UnknownBlock(_) | MoveError::DeadBlock(_) => self.error(self.tokens[1].column, format!("block [{}] does not exist", self.tokens[1].text)),
+            MoveError::OutOfBoundsCut(_, rect) => self.error(
+                last.column,
+                format!(
+                    "[{}] is out of block [{}] (x: {}..={}, y: {}..={})",
+                    last.text,
+                    self.tokens[1].text,
+                    rect.left(),
+                    rect.right(),
+                    rect.bottom(),
+                    rect.top()
+                ),
+            ),
+            MoveError::NonAdjacentMerge(_, _) => self.error(last.column, format!("block [{}] and [{}] are not adjacent", self.tokens[1].text, last.text)),
+            MoveError::ShapeMismatchedSwap(_, _) => self.error(last.column, format!("block [{}] and [{}] have different shapes", self.tokens[1].text, last.text)),
+        }
+    }
+}
+
+/// 空行とコメントを除いた (行番号, 命令) の列
+/// apply_isl で適用されるコマンドと1対1に対応する
+pub fn instruction_lines(text: &str) -> Vec<(usize, &str)> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| {
+            let trimmed = line.trim();
+            !trimmed.is_empty() && !trimmed.starts_with('#')
+        })
+        .map(|(line_index, line)| (line_index + 1, line))
+        .collect()
+}
+
+/// ISL のプログラムを init_state に順に適用する
+/// init_state は State::new, State::create_with_config, State::create_with_initial_image で作ったものを渡す
+pub fn apply_isl(text: &str, init_state: State) -> Result<State, ParseError> {
+    let mut state = init_state;
+
+    // 現在操作可能なブロックの id -> block_list の index
+    let mut index_of_id = HashMap::new();
+    for block_index in 0..state.block_list.len() {
+        if state.block_list[block_index].is_child {
+            index_of_id.insert(state.id_of(block_index), block_index);
+        }
+    }
+
+    for (line_number, line) in instruction_lines(text) {
+        let parser = LineParser {
+            line_number,
+            tokens: tokenize(line_number, line)?,
+            index_of_id: &index_of_id,
+        };
+        let cmd = parser.parse()?;
+        state.check(&cmd).map_err(|err| parser.move_error(err))?;
+
+        // 操作で id が変わる可能性のあるブロックを更新
+        let prev_len = state.block_list.len();
+        let mut touched = match cmd {
+            Command::Swap(block_index1, block_index2) | Command::Merge(block_index1, block_index2) => vec![block_index1, block_index2],
+            _ => vec![cmd.block_index()],
+        };
+        for block_index in touched.iter() {
+            index_of_id.remove(&state.id_of(*block_index));
+        }
+        state.apply(cmd);
+        touched.extend(prev_len..state.block_list.len());
+        for block_index in touched.into_iter() {
+            if state.block_list[block_index].is_child {
+                index_of_id.insert(state.id_of(block_index), block_index);
+            }
+        }
+    }
+
+    Ok(state)
+}
+
+pub fn load_isl(filepath: &Path, init_state: State) -> Result<State, ParseError> {
+    let text = std::fs::read_to_string(filepath).map_err(|err| ParseError::Io(err.to_string()))?;
+    apply_isl(&text, init_state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config_loader::TwinImageConfig, problem::StateWithScore, solution_store::SolutionStore};
+
+    fn load_serialized(problem_id: usize) -> StateWithScore {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("..");
+        SolutionStore::new(&root.join("solution"), &root.join("dataset")).load(problem_id).unwrap()
+    }
+
+    #[test]
+    fn test_apply_isl() {
+        let text = "# comment\ncut [0] [x] [100]\ncolor [0.1] [1, 2, 3, 4] \n\ncut [0.0] [50, 200]\nswap [0.0.0] [0.0.2]\nmerge [0.0.3] [0.0.0]\n";
+        let state = apply_isl(text, State::new(400, 400)).unwrap();
+
+        let mut expected = State::new(400, 400);
+        expected.apply(Command::VerticalSplit(0, 100));
+        expected.apply(Command::Color(2, Color8::new(1, 2, 3, 4)));
+        expected.apply(Command::PointSplit(1, Pos::new(200, 50)));
+        expected.apply(Command::Swap(3, 5));
+        expected.apply(Command::Merge(6, 5));
+        assert_eq!(state, expected);
+    }
+
+    #[test]
+    fn test_apply_isl_error() {
+        let err = apply_isl("cut [0] [x] [100]\ncolor [0] [0, 0, 0, 0]", State::new(400, 400)).err().unwrap();
+        assert_eq!(err.position(), Some((2, 7)));
+
+        let err = apply_isl("cut [0] [z] [100]", State::new(400, 400)).err().unwrap();
+        assert_eq!(err.position(), Some((1, 9)));
+
+        let err = apply_isl("cut [0] [y] [400]", State::new(400, 400)).err().unwrap();
+        assert_eq!(err.position(), Some((1, 13)));
+
+        let err = apply_isl("color [0] [0, 0, 256, 0]", State::new(400, 400)).err().unwrap();
+        assert_eq!(err.position(), Some((1, 11)));
+
+        let err = apply_isl("  paint [0]", State::new(400, 400)).err().unwrap();
+        assert_eq!(err.position(), Some((1, 3)));
+
+        let err = apply_isl("cut [0] [x] [100]\nmerge [0.0] [0.1\n", State::new(400, 400)).err().unwrap();
+        assert_eq!(err.position(), Some((2, 13)));
+    }
+
+    #[test]
+    fn test_load_solution() {
+        let path = format!("{}/../solution/1.txt", env!("CARGO_MANIFEST_DIR"));
+        let state = load_isl(Path::new(&path), State::new(400, 400)).unwrap();
+        assert_eq!(state.get_command_list(), load_serialized(1).state.get_command_list());
+
+        // 読めないファイルは panic せずにエラーを返す
+        let err = load_isl(Path::new("no_such_solution.txt"), State::new(400, 400)).err().unwrap();
+        assert!(matches!(err, ParseError::Io(_)));
+        assert_eq!(err.position(), None);
+    }
+
+    #[test]
+    fn test_load_twin_solution() {
+        let config_path = format!("{}/../dataset/26.initial.json", env!("CARGO_MANIFEST_DIR"));
+        let config = TwinImageConfig::load(Path::new(&config_path));
+        let path = format!("{}/../solution/26.txt", env!("CARGO_MANIFEST_DIR"));
+        let state = load_isl(Path::new(&path), State::create_with_config(&config)).unwrap();
+        assert_eq!(state.get_command_list(), load_serialized(26).state.get_command_list());
+    }
+}
@@ -1,6 +1,15 @@
+#![allow(clippy::needless_range_loop, clippy::too_many_arguments, clippy::type_complexity, clippy::ptr_arg)]
+
 pub mod clustering;
 pub mod config_loader;
 pub mod cumulative_sum;
+pub mod deadline;
+pub mod evaluator;
+pub mod history;
 pub mod intset;
+pub mod isl_parser;
+pub mod optimizer;
 pub mod problem;
+pub mod problem_loader;
 pub mod random;
+pub mod solution_store;
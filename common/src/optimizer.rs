@@ -0,0 +1,276 @@
+use crate::problem::{command_cost_list, evaluate, Color64, Color8, Command, CostModel, Image, State};
+
+/// 描画結果を変えずに命令のコストを下げる後処理. どの solver の出力にも使える
+/// 返り値は (新しい状態, 減った命令コスト)
+///
+/// 1. 消しても描画結果が変わらない color (上書きされる色、今と同じ色など) を消す
+/// 2. 分割後の子ブロックを塗っている色で分割前の親を塗り、子の color を消す.
+///    親の方が大きいので color のコストが下がる
+pub fn optimize_commands(image: &Image, state: &State, cost_model: &CostModel) -> (State, usize) {
+    let init_state = state.initial_state();
+    let target = state.to_color_buffer();
+
+    let command_list = remove_redundant_colors(&init_state, &target, state.get_command_list());
+    let command_list = hoist_colors(image, cost_model, &init_state, &target, command_list);
+    let command_list = remove_redundant_colors(&init_state, &target, command_list);
+
+    let optimized = replay(&init_state, &command_list);
+    let before = total_cost(image, state, cost_model);
+    let after = total_cost(image, &optimized, cost_model);
+    if after >= before {
+        return (state.clone(), 0);
+    }
+    (optimized, before - after)
+}
+
+// color を消したり足したりしてもブロックの index は変わらないので、そのまま再生できる
+pub fn replay(init_state: &State, command_list: &[Command]) -> State {
+    let mut state = init_state.clone();
+    for cmd in command_list.iter() {
+        state.apply(*cmd);
+    }
+    state
+}
+
+fn total_cost(image: &Image, state: &State, cost_model: &CostModel) -> usize {
+    command_cost_list(image, state, cost_model).iter().sum()
+}
+
+/// 前から順に、消しても target が変わらない color を消す
+fn remove_redundant_colors(init_state: &State, target: &Vec<Vec<Color8>>, mut command_list: Vec<Command>) -> Vec<Command> {
+    let mut prefix = init_state.clone();
+    let mut i = 0;
+    while i < command_list.len() {
+        let cmd = command_list[i];
+        if let Command::Color(_, _) = cmd {
+            let mut state = prefix.clone();
+            for cmd in command_list[i + 1..].iter() {
+                state.apply(*cmd);
+            }
+            if state.to_color_buffer() == *target {
+                command_list.remove(i);
+                continue;
+            }
+        }
+        prefix.apply(cmd);
+        i += 1;
+    }
+    command_list
+}
+
+/// 分割の直前に親を塗り、その色で最初に塗られる子の color を消す
+/// 塗る色は子の色から一番コストの下がるものを選ぶ
+fn hoist_colors(image: &Image, cost_model: &CostModel, init_state: &State, target: &Vec<Vec<Color8>>, mut command_list: Vec<Command>) -> Vec<Command> {
+    let mut current_cost = total_cost(image, &replay(init_state, &command_list), cost_model);
+    let mut prefix = init_state.clone();
+    let mut i = 0;
+    while i < command_list.len() {
+        let child_count = match command_list[i] {
+            Command::HorizontalSplit(_, _) | Command::VerticalSplit(_, _) => 2,
+            Command::PointSplit(_, _) => 4,
+            _ => 0,
+        };
+        if child_count > 0 {
+            let parent_index = command_list[i].block_index();
+            let child_range = prefix.block_list.len()..prefix.block_list.len() + child_count;
+
+            // 子ごとに最初の color の位置
+            let mut first_color_list: Vec<(usize, Color8)> = vec![];
+            let mut colored = vec![false; child_count];
+            for (j, cmd) in command_list.iter().enumerate().skip(i + 1) {
+                if let Command::Color(block_index, color) = *cmd {
+                    if child_range.contains(&block_index) && !colored[block_index - child_range.start] {
+                        colored[block_index - child_range.start] = true;
+                        first_color_list.push((j, color));
+                    }
+                }
+            }
+
+            let mut best: Option<(usize, Vec<Command>)> = None;
+            for &(_, color) in first_color_list.iter() {
+                let mut candidate = vec![];
+                for (j, cmd) in command_list.iter().enumerate() {
+                    if j == i {
+                        candidate.push(Command::Color(parent_index, color));
+                    }
+                    if !first_color_list.contains(&(j, color)) {
+                        candidate.push(*cmd);
+                    }
+                }
+                let state = replay(init_state, &candidate);
+                let cost = total_cost(image, &state, cost_model);
+                if cost < best.as_ref().map_or(current_cost, |v| v.0) && state.to_color_buffer() == *target {
+                    best = Some((cost, candidate));
+                }
+            }
+            if let Some((cost, candidate)) = best {
+                current_cost = cost;
+                command_list = candidate;
+                // 足した親の color
+                prefix.apply(command_list[i]);
+                i += 1;
+            }
+        }
+        prefix.apply(command_list[i]);
+        i += 1;
+    }
+    command_list
+}
+
+/// 各 color の色を、その色が最終的に見えている画素の幾何中央値に置き換える後処理
+/// 類似度コストは色のユークリッド距離の和なので、平均や成分ごとの中央値より幾何中央値の方が良い
+/// 返り値は (新しい状態, 下がった評価値)
+pub fn refine_colors(image: &Image, state: &State, cost_model: &CostModel) -> (State, f64) {
+    let init_state = state.initial_state();
+    let mut command_list = state.get_command_list();
+
+    // color ごとに、その色が見えている画素の目標色
+    let mut pixel_list = vec![vec![]; command_list.len()];
+    let (tag_buffer1, tag_buffer2) = (render_tags(&init_state, &command_list, 0), render_tags(&init_state, &command_list, u32::MAX));
+    for y in 0..image.height {
+        for x in 0..image.width {
+            // 初期状態の色や画像はどちらのタグでも同じ色になる
+            if tag_buffer1[y][x] != tag_buffer2[y][x] {
+                pixel_list[decode_tag(tag_buffer1[y][x], 0)].push(image.color_of(y, x).to64());
+            }
+        }
+    }
+
+    for (i, cmd) in command_list.iter_mut().enumerate() {
+        if let Command::Color(block_index, color) = *cmd {
+            if pixel_list[i].is_empty() {
+                continue;
+            }
+            let median = geometric_median(pixel_list[i].iter().map(|p| (*p, 1.0)), color.to64(), 100).round().to8();
+            if distance_sum(&pixel_list[i], &median) < distance_sum(&pixel_list[i], &color) {
+                *cmd = Command::Color(block_index, median);
+            }
+        }
+    }
+
+    let refined = replay(&init_state, &command_list);
+    let gain = evaluate(image, state, cost_model) - evaluate(image, &refined, cost_model);
+    if gain <= 0.0 {
+        return (state.clone(), 0.0);
+    }
+    (refined, gain)
+}
+
+// i 番目の命令の色を (i + 1) ^ mask にした状態を描画する. 0 ^ mask は初期状態の色
+fn render_tags(init_state: &State, command_list: &[Command], mask: u32) -> Vec<Vec<Color8>> {
+    let mut state = init_state.clone();
+    for (i, cmd) in command_list.iter().enumerate() {
+        match *cmd {
+            Command::Color(block_index, _) => {
+                let tag = (i as u32 + 1) ^ mask;
+                state.apply(Command::Color(block_index, Color8::new((tag >> 24) as u8, (tag >> 16) as u8, (tag >> 8) as u8, tag as u8)))
+            }
+            _ => state.apply(*cmd),
+        }
+    }
+    state.to_color_buffer()
+}
+
+fn decode_tag(color: Color8, mask: u32) -> usize {
+    let tag = ((color.r as u32) << 24 | (color.g as u32) << 16 | (color.b as u32) << 8 | color.a as u32) ^ mask;
+    tag as usize - 1
+}
+
+fn distance_sum(pixel_list: &[Color64], color: &Color8) -> f64 {
+    let color = color.to64();
+    pixel_list.iter().map(|p| (*p - color).square().horizontal_add().sqrt()).sum()
+}
+
+/// Weiszfeld 法で (色, 重み) の重み付き幾何中央値を求める
+/// point_iter は反復ごとに clone して何度も回す
+pub fn geometric_median<I: Iterator<Item = (Color64, f64)> + Clone>(point_iter: I, init: Color64, max_iteration: usize) -> Color64 {
+    const EPS: f64 = 1e-6;
+
+    let mut median = init;
+    for _ in 0..max_iteration {
+        let mut numerator = Color64::default();
+        let mut denominator = 0.0;
+        for (p, weight) in point_iter.clone() {
+            let distance = (p - median).square().horizontal_add().sqrt();
+            // 中央値がちょうど画素に乗ったときは、その画素を無視する
+            if distance < EPS {
+                continue;
+            }
+            numerator += p * (weight / distance);
+            denominator += weight / distance;
+        }
+        if denominator == 0.0 {
+            break;
+        }
+        let next = numerator / denominator;
+        let diff = (next - median).square().horizontal_add().sqrt();
+        median = next;
+        if diff < 1e-3 {
+            break;
+        }
+    }
+    median
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::problem::Rectangle;
+
+    #[test]
+    fn test_optimize_commands() {
+        let image = Image {
+            height: 400,
+            width: 400,
+            buffer: vec![Color8::new(255, 0, 0, 255); 400 * 400],
+        };
+        let red = Color8::new(255, 0, 0, 255);
+        let blue = Color8::new(0, 0, 255, 255);
+        let cost_model = CostModel::default();
+
+        let mut state = State::new(400, 400);
+        state.apply(Command::VerticalSplit(0, 200));
+        state.apply(Command::Color(1, red));
+        state.apply(Command::Color(2, blue));
+        state.apply(Command::Color(2, red));
+        state.apply(Command::Color(2, red));
+
+        let (optimized, saved_cost) = optimize_commands(&image, &state, &cost_model);
+        assert_eq!(optimized.get_command_list(), vec![Command::Color(0, red), Command::VerticalSplit(0, 200)]);
+        assert_eq!(optimized.to_color_buffer(), state.to_color_buffer());
+        assert_eq!(evaluate(&image, &state, &cost_model) - evaluate(&image, &optimized, &cost_model), saved_cost as f64);
+
+        // 何も消せないときはそのまま
+        let (same, saved_cost) = optimize_commands(&image, &optimized, &cost_model);
+        assert_eq!(same, optimized);
+        assert_eq!(saved_cost, 0);
+    }
+
+    #[test]
+    fn test_refine_colors() {
+        // 左半分の 3/4 を赤、残りを青にした画像を平均色で塗った状態
+        let red = Color8::new(255, 0, 0, 255);
+        let blue = Color8::new(0, 0, 255, 255);
+        let mut image = Image {
+            height: 400,
+            width: 400,
+            buffer: vec![blue; 400 * 400],
+        };
+        for y in 0..300 {
+            for x in 0..200 {
+                image.buffer[y * 400 + x] = red;
+            }
+        }
+        let cost_model = CostModel::default();
+
+        let mut state = State::new(400, 400);
+        state.apply(Command::VerticalSplit(0, 200));
+        state.apply(Command::Color(1, image.mean_color(&Rectangle::new(0, 0, 400, 200))));
+        state.apply(Command::Color(2, blue));
+
+        let (refined, gain) = refine_colors(&image, &state, &cost_model);
+        assert!(gain > 0.0);
+        assert_eq!(refined.get_command_list(), vec![Command::VerticalSplit(0, 200), Command::Color(1, red), Command::Color(2, blue)]);
+        assert_eq!(evaluate(&image, &state, &cost_model) - evaluate(&image, &refined, &cost_model), gain);
+    }
+}
@@ -1,19 +1,60 @@
-pub const LINE_CUT_COST: f64 = 7.0;
-pub const POINT_CUT_COST: f64 = 10.0;
-pub const COLOR_COST: f64 = 5.0;
-pub const SWAP_COST: f64 = 3.0;
-pub const MERGE_COST: f64 = 1.0;
 pub const ALPHA: f64 = 0.005;
 
 use png::ColorType;
 use std::{
+    fmt::{self, Write},
     fs::File,
-    io::{BufReader, BufWriter, Write},
+    io::{BufReader, BufWriter},
     ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Sub, SubAssign},
     path::Path,
+    sync::Arc,
 };
 
-use crate::config_loader;
+use crate::{config_loader, history::RunInfo, solution_store::SolutionStore};
+
+/// 各命令の基本コスト
+/// 問題ごとに dataset/{id}.cost.json があればそちらを使う
+#[derive(Clone, Copy, PartialEq, Eq, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct CostModel {
+    pub line_cut: usize,
+    pub point_cut: usize,
+    pub color: usize,
+    pub swap: usize,
+    pub merge: usize,
+}
+
+impl Default for CostModel {
+    fn default() -> Self {
+        CostModel {
+            line_cut: 7,
+            point_cut: 10,
+            color: 5,
+            swap: 3,
+            merge: 1,
+        }
+    }
+}
+
+impl CostModel {
+    fn path_of_json(problem_id: usize) -> String {
+        format!("dataset/{}.cost.json", problem_id)
+    }
+
+    pub fn load(problem_id: usize) -> CostModel {
+        let path_str = Self::path_of_json(problem_id);
+        if Path::new(&path_str).exists() {
+            Self::load_from(Path::new(&path_str))
+        } else {
+            CostModel::default()
+        }
+    }
+
+    pub fn load_from(filepath: &Path) -> CostModel {
+        let file = File::open(filepath).unwrap();
+        serde_json::from_reader(BufReader::new(file)).unwrap()
+    }
+}
 
 #[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize)]
 pub struct Color<T> {
@@ -266,7 +307,7 @@ impl Default for Color<u8> {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Debug)]
 pub struct Image {
     pub height: usize,
     pub width: usize,
@@ -275,11 +316,19 @@ pub struct Image {
 
 impl Image {
     pub fn new(filepath: &str) -> Image {
-        let decoder = png::Decoder::new(File::open(filepath).unwrap());
-        let mut reader = decoder.read_info().unwrap();
+        Image::try_new(filepath).unwrap()
+    }
+
+    /// 読めない / RGBA でない png はエラー
+    pub fn try_new(filepath: &str) -> Result<Image, String> {
+        let file = File::open(filepath).map_err(|err| format!("{}: {}", filepath, err))?;
+        let decoder = png::Decoder::new(file);
+        let mut reader = decoder.read_info().map_err(|err| format!("{}: {}", filepath, err))?;
         let mut raw_buffer = vec![0; reader.output_buffer_size()];
-        let info = reader.next_frame(&mut raw_buffer).unwrap();
-        assert!(info.color_type == ColorType::Rgba);
+        let info = reader.next_frame(&mut raw_buffer).map_err(|err| format!("{}: {}", filepath, err))?;
+        if info.color_type != ColorType::Rgba {
+            return Err(format!("{}: not an RGBA png", filepath));
+        }
 
         //
         let height = info.height as usize;
@@ -298,7 +347,7 @@ impl Image {
                 buffer[dst_i] = Color::new(r, g, b, a);
             }
         }
-        Image { height, width, buffer }
+        Ok(Image { height, width, buffer })
     }
 
     pub fn size(&self) -> usize {
@@ -339,11 +388,11 @@ impl Image {
     pub fn save_image(&self, filepath: &String) {
         let path = Path::new(filepath);
         let file = File::create(path).unwrap();
-        let ref mut writer = BufWriter::new(file);
+        let writer = &mut BufWriter::new(file);
         let mut encoder = png::Encoder::new(writer, self.width as u32, self.height as u32);
         encoder.set_color(png::ColorType::Rgba);
         encoder.set_depth(png::BitDepth::Eight);
-        encoder.set_trns(vec![0xFFu8, 0xFFu8, 0xFFu8, 0xFFu8]);
+        // RGBA の png に tRNS は付けられない (付けると png crate で読めない)
         let mut writer = encoder.write_header().unwrap();
 
         let mut raw_data = vec![];
@@ -442,6 +491,24 @@ impl Rectangle {
         (bottom_left, bottom_right, top_right, top_left)
     }
 
+    /// rect2 が self から見てどの方向に接続できるか
+    fn merge_direction(&self, rect2: &Rectangle) -> Option<Direction> {
+        let vertical_adjusted = self.left() == rect2.left() && self.right() == rect2.right();
+        let horizontal_adjustted = self.top() == rect2.top() && self.bottom() == rect2.bottom();
+
+        if self.bottom() == rect2.top() + 1 && vertical_adjusted {
+            Some(Direction::Down)
+        } else if self.top() + 1 == rect2.bottom() && vertical_adjusted {
+            Some(Direction::Up)
+        } else if self.left() == rect2.right() + 1 && horizontal_adjustted {
+            Some(Direction::Left)
+        } else if self.right() + 1 == rect2.left() && horizontal_adjustted {
+            Some(Direction::Right)
+        } else {
+            None
+        }
+    }
+
     // dir: self から見てどの方向に接続するか
     fn merge(&self, rect2: &Rectangle, dir: Direction) -> Rectangle {
         match dir {
@@ -453,7 +520,21 @@ impl Rectangle {
     }
 }
 
-/// FIXME: merge 操作を特別視する. enum 用意する？
+/// ブロックに表示されている内容
+/// Part, Merged が参照するのは分割 / merge 済みのブロックで、それらの rect はもう変化しない
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+pub enum BlockContent {
+    // Block::color 一色
+    #[default]
+    Color,
+    // rect.bottom_left に対応する初期画像上の位置
+    Texture(Pos),
+    // 分割元のブロックの一部. block_idx, rect.bottom_left に対応する位置
+    Part(usize, Pos),
+    // merge 元の 2 ブロック. block_idx, block_idx, rect.bottom_left に対応する位置
+    Merged(usize, usize, Pos),
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Block {
     pub rect: Rectangle,
@@ -466,14 +547,29 @@ pub struct Block {
     pub is_child: bool,
     // 管理用配列の index
     pub index_of: usize,
+    // color 以外を表示している場合の内容
+    #[serde(default)]
+    pub content: BlockContent,
 }
 
 impl Block {
+    /// 分割後の rect に表示する内容
+    fn child_content(&self, rect: &Rectangle) -> BlockContent {
+        let shift = |pos: Pos| Pos::new(pos.y + rect.bottom() - self.rect.bottom(), pos.x + rect.left() - self.rect.left());
+        match self.content {
+            BlockContent::Color => BlockContent::Color,
+            BlockContent::Texture(pos) => BlockContent::Texture(shift(pos)),
+            BlockContent::Part(block_index, pos) => BlockContent::Part(block_index, shift(pos)),
+            BlockContent::Merged(_, _, _) => BlockContent::Part(self.index_of, rect.bottom_left),
+        }
+    }
+
     pub fn vertical_split(&self, x: usize, child_start_index: usize) -> (Block, Block) {
         let (left_rect, right_rect) = self.rect.vertical_split(x);
         let left_block = Block {
             rect: left_rect,
             color: self.color,
+            content: self.child_content(&left_rect),
             parent: Some(self.index_of),
             id: 0,
             is_child: true,
@@ -482,6 +578,7 @@ impl Block {
         let right_block = Block {
             rect: right_rect,
             color: self.color,
+            content: self.child_content(&right_rect),
             parent: Some(self.index_of),
             id: 1,
             is_child: true,
@@ -495,6 +592,7 @@ impl Block {
         let bottom_block = Block {
             rect: bottom_rect,
             color: self.color,
+            content: self.child_content(&bottom_rect),
             parent: Some(self.index_of),
             id: 0,
             is_child: true,
@@ -503,6 +601,7 @@ impl Block {
         let top_block = Block {
             rect: top_rect,
             color: self.color,
+            content: self.child_content(&top_rect),
             parent: Some(self.index_of),
             id: 1,
             is_child: true,
@@ -516,6 +615,7 @@ impl Block {
         let bl_block = Block {
             rect: bl_rect,
             color: self.color,
+            content: self.child_content(&bl_rect),
             parent: Some(self.index_of),
             id: 0,
             is_child: true,
@@ -524,6 +624,7 @@ impl Block {
         let br_block = Block {
             rect: br_rect,
             color: self.color,
+            content: self.child_content(&br_rect),
             parent: Some(self.index_of),
             id: 1,
             is_child: true,
@@ -532,6 +633,7 @@ impl Block {
         let tr_block = Block {
             rect: tr_rect,
             color: self.color,
+            content: self.child_content(&tr_rect),
             parent: Some(self.index_of),
             id: 2,
             is_child: true,
@@ -540,6 +642,7 @@ impl Block {
         let tl_block = Block {
             rect: tl_rect,
             color: self.color,
+            content: self.child_content(&tl_rect),
             parent: Some(self.index_of),
             id: 3,
             is_child: true,
@@ -567,14 +670,14 @@ pub enum Command {
 }
 
 impl Command {
-    pub fn base_cost(&self) -> usize {
+    pub fn base_cost(&self, cost_model: &CostModel) -> usize {
         match *self {
-            Command::HorizontalSplit(_, _) => 7,
-            Command::VerticalSplit(_, _) => 7,
-            Command::PointSplit(_, _) => 10,
-            Command::Color(_, _) => 5,
-            Command::Swap(_, _) => 3,
-            Command::Merge(_, _) => 1,
+            Command::HorizontalSplit(_, _) => cost_model.line_cut,
+            Command::VerticalSplit(_, _) => cost_model.line_cut,
+            Command::PointSplit(_, _) => cost_model.point_cut,
+            Command::Color(_, _) => cost_model.color,
+            Command::Swap(_, _) => cost_model.swap,
+            Command::Merge(_, _) => cost_model.merge,
         }
     }
 
@@ -591,6 +694,43 @@ impl Command {
     }
 }
 
+/// 実行できない操作
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MoveError {
+    // block_idx
+    UnknownBlock(usize),
+    // block_idx
+    DeadBlock(usize),
+    // block_idx, block の領域
+    OutOfBoundsCut(usize, Rectangle),
+    // block_idx, block_idx
+    NonAdjacentMerge(usize, usize),
+    // block_idx, block_idx
+    ShapeMismatchedSwap(usize, usize),
+}
+
+impl fmt::Display for MoveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            MoveError::UnknownBlock(block_index) => write!(f, "block {} does not exist", block_index),
+            MoveError::DeadBlock(block_index) => write!(f, "block {} is already split or merged", block_index),
+            MoveError::OutOfBoundsCut(block_index, rect) => write!(
+                f,
+                "cut is out of block {} (x: {}..={}, y: {}..={})",
+                block_index,
+                rect.left(),
+                rect.right(),
+                rect.bottom(),
+                rect.top()
+            ),
+            MoveError::NonAdjacentMerge(block_index1, block_index2) => write!(f, "block {} and {} are not adjacent", block_index1, block_index2),
+            MoveError::ShapeMismatchedSwap(block_index1, block_index2) => write!(f, "block {} and {} have different shapes", block_index1, block_index2),
+        }
+    }
+}
+
+impl std::error::Error for MoveError {}
+
 #[derive(Clone, PartialEq, Eq, Debug, serde::Serialize, serde::Deserialize)]
 enum CommandWithLog {
     // block_idx, y
@@ -605,31 +745,12 @@ enum CommandWithLog {
     Swap(usize, usize),
     // block_idx, block_idx
     Merge(usize, usize),
+    // 一色でないブロックを塗った場合
+    // block_idx, prev_color, prev_content, color
+    ColorOverContent(usize, Color8, BlockContent, Color8),
 }
 
 impl CommandWithLog {
-    pub fn base_cost(&self) -> usize {
-        match *self {
-            CommandWithLog::HorizontalSplit(_, _) => 7,
-            CommandWithLog::VerticalSplit(_, _) => 7,
-            CommandWithLog::PointSplit(_, _) => 10,
-            CommandWithLog::Color(_, _, _) => 5,
-            CommandWithLog::Swap(_, _) => 3,
-            CommandWithLog::Merge(_, _) => 1,
-        }
-    }
-
-    pub fn block_index(&self) -> usize {
-        match *self {
-            CommandWithLog::HorizontalSplit(block_index, _) => block_index,
-            CommandWithLog::VerticalSplit(block_index, _) => block_index,
-            CommandWithLog::PointSplit(block_index, _) => block_index,
-            CommandWithLog::Color(block_index, _, _) => block_index,
-            CommandWithLog::Swap(block_index, _) => block_index,
-            CommandWithLog::Merge(block_index, _) => block_index,
-        }
-    }
-
     pub fn to_command(&self) -> Command {
         match *self {
             CommandWithLog::HorizontalSplit(block_index, y) => Command::HorizontalSplit(block_index, y),
@@ -638,6 +759,7 @@ impl CommandWithLog {
             CommandWithLog::Color(block_index, _, color) => Command::Color(block_index, color),
             CommandWithLog::Swap(block_index1, block_index2) => Command::Swap(block_index1, block_index2),
             CommandWithLog::Merge(block_index1, block_index2) => Command::Merge(block_index1, block_index2),
+            CommandWithLog::ColorOverContent(block_index, _, _, color) => Command::Color(block_index, color),
         }
     }
 }
@@ -647,6 +769,9 @@ pub struct State {
     pub block_list: Vec<Block>,
     next_block_id: usize,
     command_list: Vec<CommandWithLog>,
+    // 初期画像が png で与えられる問題のみ
+    #[serde(skip)]
+    initial_image: Option<Arc<Image>>,
 }
 
 impl State {
@@ -662,11 +787,13 @@ impl State {
             id: 0,
             is_child: true,
             index_of: 0,
+            content: BlockContent::Color,
         };
         State {
             block_list: vec![init_block],
             command_list: vec![],
             next_block_id: 1,
+            initial_image: None,
         }
     }
 
@@ -674,6 +801,44 @@ impl State {
         self.command_list.iter().map(|cmd| cmd.to_command()).collect()
     }
 
+    /// 命令を全部戻した初期状態
+    pub fn initial_state(&self) -> State {
+        let mut ret = self.clone();
+        while !ret.command_list.is_empty() {
+            ret.undo();
+        }
+        ret
+    }
+
+    /// 命令を適用する前のブロック数
+    /// create_with_config で作った場合は、使わない 0 番のブロックも含む
+    pub fn initial_block_count(&self) -> usize {
+        let created_count: usize = self
+            .command_list
+            .iter()
+            .map(|cmd| match cmd {
+                CommandWithLog::HorizontalSplit(_, _) | CommandWithLog::VerticalSplit(_, _) => 2,
+                CommandWithLog::PointSplit(_, _) => 4,
+                CommandWithLog::Merge(_, _) => 1,
+                _ => 0,
+            })
+            .sum();
+        self.block_list.len() - created_count
+    }
+
+    /// cmd のコスト計算に使うブロックの大きさ
+    /// merge は大きい方のブロックで計算する
+    pub fn block_size_for_cost(&self, cmd: &Command) -> usize {
+        match *cmd {
+            Command::Merge(block_index1, block_index2) => self.block_list[block_index1].rect.size().max(self.block_list[block_index2].rect.size()),
+            _ => self.block_list[cmd.block_index()].rect.size(),
+        }
+    }
+
+    pub fn last_command(&self) -> Option<Command> {
+        self.command_list.last().map(|cmd| cmd.to_command())
+    }
+
     pub fn create_with_config(config: &config_loader::TwinImageConfig) -> State {
         let mut state = State::new(config.height, config.width);
         state.block_list[0].is_child = false;
@@ -686,6 +851,7 @@ impl State {
                 id: block_config.id,
                 is_child: true,
                 index_of: state.block_list.len(),
+                content: block_config.texture.map_or(BlockContent::Color, BlockContent::Texture),
             });
             state.next_block_id = state.next_block_id.max(block_config.id);
         }
@@ -694,7 +860,117 @@ impl State {
         state
     }
 
+    /// 初期画像が png で与えられる問題 (36..40) 用
+    /// config で texture を持つブロックは initial_image の画素を表示する
+    pub fn create_with_initial_image(config: &config_loader::TwinImageConfig, initial_image: &Image) -> State {
+        assert_eq!((config.height, config.width), (initial_image.height, initial_image.width));
+        let mut state = State::create_with_config(config);
+        state.initial_image = Some(Arc::new(initial_image.clone()));
+        state
+    }
+
+    /// block 上の (y, x) に表示される色
+    fn color_in_block(&self, block: &Block, y: usize, x: usize) -> Color8 {
+        let shift = |pos: Pos| Pos::new(pos.y + y - block.rect.bottom(), pos.x + x - block.rect.left());
+        match block.content {
+            BlockContent::Color => block.color,
+            BlockContent::Texture(pos) => {
+                let initial_image = self.initial_image.as_ref().expect("initial image is not loaded");
+                initial_image.color_of_pos(&shift(pos))
+            }
+            BlockContent::Part(block_index, pos) => {
+                let pos = shift(pos);
+                self.color_in_block(&self.block_list[block_index], pos.y, pos.x)
+            }
+            BlockContent::Merged(block_index1, block_index2, pos) => {
+                let pos = shift(pos);
+                let block1 = &self.block_list[block_index1];
+                let src = if block1.rect.contains(&pos) { block1 } else { &self.block_list[block_index2] };
+                self.color_in_block(src, pos.y, pos.x)
+            }
+        }
+    }
+
+    /// block_index のブロックと image の画素コスト (ALPHA を掛ける前)
+    pub fn block_rmse(&self, image: &Image, block_index: usize) -> f64 {
+        let block = &self.block_list[block_index];
+        if block.content == BlockContent::Color {
+            return image.rmse(&block.rect, &block.color);
+        }
+        let rect = block.rect;
+        let mut sum = 0f64;
+        for y in rect.bottom()..=rect.top() {
+            for x in rect.left()..=rect.right() {
+                let diff = self.color_in_block(block, y, x).to64() - image.color_of(y, x).to64();
+                sum += diff.square().horizontal_add().sqrt();
+            }
+        }
+        sum
+    }
+
+    /// cmd が実行可能か確認する
+    pub fn check(&self, cmd: &Command) -> Result<(), MoveError> {
+        let check_alive = |block_index: usize| -> Result<Rectangle, MoveError> {
+            if block_index >= self.block_list.len() {
+                Err(MoveError::UnknownBlock(block_index))
+            } else if !self.block_list[block_index].is_child {
+                Err(MoveError::DeadBlock(block_index))
+            } else {
+                Ok(self.block_list[block_index].rect)
+            }
+        };
+
+        match *cmd {
+            Command::HorizontalSplit(block_index, y) => {
+                let rect = check_alive(block_index)?;
+                if !(rect.bottom() < y && y < rect.top()) {
+                    return Err(MoveError::OutOfBoundsCut(block_index, rect));
+                }
+            }
+            Command::VerticalSplit(block_index, x) => {
+                let rect = check_alive(block_index)?;
+                if !(rect.left() < x && x < rect.right()) {
+                    return Err(MoveError::OutOfBoundsCut(block_index, rect));
+                }
+            }
+            Command::PointSplit(block_index, pos) => {
+                let rect = check_alive(block_index)?;
+                if !rect.is_internal(&pos) {
+                    return Err(MoveError::OutOfBoundsCut(block_index, rect));
+                }
+            }
+            Command::Color(block_index, _) => {
+                check_alive(block_index)?;
+            }
+            Command::Swap(block_index1, block_index2) => {
+                let rect1 = check_alive(block_index1)?;
+                let rect2 = check_alive(block_index2)?;
+                if rect1.height != rect2.height || rect1.width != rect2.width {
+                    return Err(MoveError::ShapeMismatchedSwap(block_index1, block_index2));
+                }
+            }
+            Command::Merge(block_index1, block_index2) => {
+                let rect1 = check_alive(block_index1)?;
+                let rect2 = check_alive(block_index2)?;
+                if rect1.merge_direction(&rect2).is_none() {
+                    return Err(MoveError::NonAdjacentMerge(block_index1, block_index2));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 実行できない cmd の場合は何もせずにエラーを返す
+    pub fn try_apply(&mut self, cmd: Command) -> Result<(), MoveError> {
+        self.check(&cmd)?;
+        self.apply(cmd);
+        Ok(())
+    }
+
     pub fn apply(&mut self, cmd: Command) {
+        if let Err(err) = self.check(&cmd) {
+            panic!("invalid move {:?}: {}", cmd, err);
+        }
         match cmd {
             Command::HorizontalSplit(block_index, y) => {
                 self.horizontal_split(block_index, y);
@@ -710,8 +986,13 @@ impl State {
             }
             Command::Color(block_index, color) => {
                 let prev_color = self.block_list[block_index].color;
+                let prev_content = self.block_list[block_index].content;
                 self.color(block_index, &prev_color, &color);
-                self.command_list.push(CommandWithLog::Color(block_index, prev_color, color));
+                if prev_content == BlockContent::Color {
+                    self.command_list.push(CommandWithLog::Color(block_index, prev_color, color));
+                } else {
+                    self.command_list.push(CommandWithLog::ColorOverContent(block_index, prev_color, prev_content, color));
+                }
             }
             Command::Swap(block_index1, block_index2) => {
                 self.swap(block_index1, block_index2);
@@ -747,11 +1028,25 @@ impl State {
                 assert!(self.block_list[block_index].is_child);
                 self.block_list[block_index].color = prev_color;
             }
-            CommandWithLog::Swap(_, _) => {
-                unimplemented!();
+            CommandWithLog::ColorOverContent(block_index, prev_color, prev_content, _) => {
+                assert!(self.block_list[block_index].is_child);
+                self.block_list[block_index].color = prev_color;
+                self.block_list[block_index].content = prev_content;
+            }
+            CommandWithLog::Swap(block_index1, block_index2) => {
+                // 同じものを入れ替えると元に戻る
+                self.swap(block_index1, block_index2);
             }
-            CommandWithLog::Merge(_, _) => {
-                unimplemented!();
+            CommandWithLog::Merge(block_index1, block_index2) => {
+                let merged_block = self.block_list.pop().unwrap();
+                assert!(merged_block.is_child);
+                assert!(merged_block.id + 1 == self.next_block_id);
+                self.next_block_id -= 1;
+
+                assert!(!self.block_list[block_index1].is_child);
+                assert!(!self.block_list[block_index2].is_child);
+                self.block_list[block_index1].is_child = true;
+                self.block_list[block_index2].is_child = true;
             }
         }
         self.command_list.pop();
@@ -761,12 +1056,13 @@ impl State {
         assert!(block_index < self.block_list.len());
         assert!(self.block_list[block_index].color == *prev_color);
         self.block_list[block_index].color = *color;
+        self.block_list[block_index].content = BlockContent::Color;
     }
 
     fn horizontal_split(&mut self, block_index: usize, y: usize) {
         assert!(block_index < self.block_list.len());
         let len = self.block_list.len();
-        let mut parent_block = &mut self.block_list[block_index];
+        let parent_block = &mut self.block_list[block_index];
         assert!(parent_block.index_of == block_index);
         assert!(parent_block.rect.bottom() < y && y < parent_block.rect.top());
         parent_block.is_child = false;
@@ -779,7 +1075,7 @@ impl State {
     fn vertical_split(&mut self, block_index: usize, x: usize) {
         assert!(block_index < self.block_list.len());
         let len = self.block_list.len();
-        let mut parent_block = &mut self.block_list[block_index];
+        let parent_block = &mut self.block_list[block_index];
         assert!(parent_block.index_of == block_index);
         assert!(parent_block.rect.left() < x && x < parent_block.rect.right());
         parent_block.is_child = false;
@@ -792,7 +1088,7 @@ impl State {
     fn point_cut(&mut self, block_index: usize, pos: &Pos) {
         assert!(block_index < self.block_list.len());
         let len = self.block_list.len();
-        let mut parent_block = &mut self.block_list[block_index];
+        let parent_block = &mut self.block_list[block_index];
         assert!(parent_block.index_of == block_index);
         assert!(parent_block.rect.is_internal(pos));
         assert!(parent_block.is_child);
@@ -808,37 +1104,31 @@ impl State {
         let rect1 = self.block_list[block_index1].rect;
         let rect2 = self.block_list[block_index2].rect;
 
-        let vertical_adjusted = rect1.left() == rect2.left() && rect1.right() == rect2.right();
-        let horizontal_adjustted = rect1.top() == rect2.top() && rect1.bottom() == rect2.bottom();
-
-        let rect1_bottom_connectable = rect1.bottom() == rect2.top() + 1 && vertical_adjusted;
-        let rect1_top_connectable = rect1.top() + 1 == rect2.bottom() && vertical_adjusted;
-        let rect1_left_connectable = rect1.left() == rect2.right() + 1 && horizontal_adjustted;
-        let rect1_right_connectable = rect1.right() + 1 == rect2.left() && horizontal_adjustted;
-
-        assert!(rect1_bottom_connectable || rect1_top_connectable || rect1_left_connectable || rect1_right_connectable);
+        let dir = rect1.merge_direction(&rect2).unwrap();
 
         self.block_list[block_index1].is_child = false;
         self.block_list[block_index2].is_child = false;
 
-        let dir = if rect1_bottom_connectable {
-            Direction::Down
-        } else if rect1_top_connectable {
-            Direction::Up
-        } else if rect1_left_connectable {
-            Direction::Left
-        } else {
-            Direction::Right
+        let rect = rect1.merge(&rect2, dir);
+        let block1 = &self.block_list[block_index1];
+        let block2 = &self.block_list[block_index2];
+        let content = match (block1.content, block2.content) {
+            (BlockContent::Color, BlockContent::Color) if block1.color == block2.color => BlockContent::Color,
+            // 初期画像上でも隣り合っていれば、そのまま1枚の画像として扱える
+            (BlockContent::Texture(t1), BlockContent::Texture(t2)) if t1.y + rect2.bottom() == t2.y + rect1.bottom() && t1.x + rect2.left() == t2.x + rect1.left() => {
+                BlockContent::Texture(Pos::new(t1.y + rect.bottom() - rect1.bottom(), t1.x + rect.left() - rect1.left()))
+            }
+            _ => BlockContent::Merged(block_index1, block_index2, rect.bottom_left),
         };
 
-        // FIXME: merge 後のオブジェクトには必ず色を付ける制約がある(整合性が取れていない)
         let merged_block = Block {
-            rect: rect1.merge(&rect2, dir),
-            color: self.block_list[block_index1].color,
+            rect,
+            color: block1.color,
             parent: None, // fixme: 整合性確認
             id: self.next_block_id,
             is_child: true,
             index_of: self.block_list.len(),
+            content,
         };
         self.next_block_id += 1;
         self.block_list.push(merged_block);
@@ -863,7 +1153,7 @@ impl State {
     pub fn save_image(&self, image_fliepath: &String) {
         let path = Path::new(image_fliepath);
         let file = File::create(path).unwrap();
-        let ref mut writer = BufWriter::new(file);
+        let writer = &mut BufWriter::new(file);
         let data = self.to_color_buffer();
         let height = data.len();
         let width = data[0].len();
@@ -871,7 +1161,7 @@ impl State {
 
         encoder.set_color(png::ColorType::Rgba);
         encoder.set_depth(png::BitDepth::Eight);
-        encoder.set_trns(vec![0xFFu8, 0xFFu8, 0xFFu8, 0xFFu8]);
+        // RGBA の png に tRNS は付けられない (付けると png crate で読めない)
         let mut writer = encoder.write_header().unwrap();
 
         let mut raw_data = vec![];
@@ -885,55 +1175,54 @@ impl State {
         writer.write_image_data(&raw_data).unwrap();
     }
 
-    pub fn print_output(&self, filepath: &Path) {
-        let restore_id_sequence = |block_index: usize| -> String {
-            let mut id_list = vec![];
-            let mut index = block_index;
-            loop {
-                id_list.push(self.block_list[index].id);
-                if let Some(parent_block_index) = self.block_list[index].parent {
-                    index = parent_block_index;
-                } else {
-                    break;
-                }
+    /// 出力用の "0.1.3" 形式の block id を復元する
+    pub fn id_of(&self, block_index: usize) -> String {
+        let mut id_list = vec![];
+        let mut index = block_index;
+        loop {
+            id_list.push(self.block_list[index].id);
+            if let Some(parent_block_index) = self.block_list[index].parent {
+                index = parent_block_index;
+            } else {
+                break;
             }
-            id_list.reverse();
-            id_list.into_iter().map(|v: usize| v.to_string()).collect::<Vec<_>>().join(".")
-        };
-
-        let file = File::create(filepath).unwrap();
-        let mut writer = BufWriter::new(file);
+        }
+        id_list.reverse();
+        id_list.into_iter().map(|v: usize| v.to_string()).collect::<Vec<_>>().join(".")
+    }
 
-        for cmd in self.command_list.iter() {
-            match *cmd {
-                CommandWithLog::HorizontalSplit(block_index, y) => {
-                    let block_id = restore_id_sequence(block_index);
-                    writeln!(&mut writer, "cut [{}] [y] [{}]", block_id, y).unwrap();
-                }
-                CommandWithLog::VerticalSplit(block_index, x) => {
-                    let block_id = restore_id_sequence(block_index);
-                    writeln!(&mut writer, "cut [{}] [x] [{}]", block_id, x).unwrap();
-                }
-                CommandWithLog::PointSplit(block_index, pos) => {
-                    let block_id = restore_id_sequence(block_index);
-                    writeln!(&mut writer, "cut [{}] [{}, {}]", block_id, pos.x, pos.y).unwrap();
-                }
-                CommandWithLog::Color(block_index, _, color) => {
-                    let block_id = restore_id_sequence(block_index);
-                    writeln!(&mut writer, "color [{}] [{}, {}, {}, {}] ", block_id, color.r, color.g, color.b, color.a).unwrap();
+    pub fn print_output(&self, filepath: &Path) {
+        std::fs::write(filepath, self.to_isl()).unwrap();
+    }
+
+    /// 出力する ISL の文字列
+    pub fn to_isl(&self) -> String {
+        // swap で id が入れ替わるので、後ろから swap を戻しながら各命令の時点の id を求める
+        let mut id_list = (0..self.block_list.len()).map(|block_index| self.id_of(block_index)).collect::<Vec<_>>();
+        let mut line_list = vec![];
+
+        for cmd in self.command_list.iter().rev() {
+            let line = match *cmd {
+                CommandWithLog::HorizontalSplit(block_index, y) => format!("cut [{}] [y] [{}]", id_list[block_index], y),
+                CommandWithLog::VerticalSplit(block_index, x) => format!("cut [{}] [x] [{}]", id_list[block_index], x),
+                CommandWithLog::PointSplit(block_index, pos) => format!("cut [{}] [{}, {}]", id_list[block_index], pos.x, pos.y),
+                CommandWithLog::Color(block_index, _, color) | CommandWithLog::ColorOverContent(block_index, _, _, color) => {
+                    format!("color [{}] [{}, {}, {}, {}] ", id_list[block_index], color.r, color.g, color.b, color.a)
                 }
                 CommandWithLog::Swap(block_index1, block_index2) => {
-                    let block_id1 = restore_id_sequence(block_index1);
-                    let block_id2 = restore_id_sequence(block_index2);
-                    writeln!(&mut writer, "swap [{}] [{}]", block_id1, block_id2).unwrap();
-                }
-                CommandWithLog::Merge(block_index1, block_index2) => {
-                    let block_id1 = restore_id_sequence(block_index1);
-                    let block_id2 = restore_id_sequence(block_index2);
-                    writeln!(&mut writer, "merge [{}] [{}]", block_id1, block_id2).unwrap();
+                    id_list.swap(block_index1, block_index2);
+                    format!("swap [{}] [{}]", id_list[block_index1], id_list[block_index2])
                 }
-            }
+                CommandWithLog::Merge(block_index1, block_index2) => format!("merge [{}] [{}]", id_list[block_index1], id_list[block_index2]),
+            };
+            line_list.push(line);
+        }
+
+        let mut ret = String::new();
+        for line in line_list.iter().rev() {
+            writeln!(&mut ret, "{}", line).unwrap();
         }
+        ret
     }
 
     pub fn to_color_buffer(&self) -> Vec<Vec<Color8>> {
@@ -941,12 +1230,12 @@ impl State {
         let height = self.block_list[0].rect.height;
 
         let mut ret = vec![vec![Color8::default(); width]; height];
-        // FIXME: 色の変化があったブロックを追従する
-        for block in self.block_list.iter() {
+        // 生きているブロックが canvas 全体を覆っている
+        for block in self.block_list.iter().filter(|block| block.is_child) {
             let rect = block.rect;
             for y in rect.bottom()..=rect.top() {
                 for x in rect.left()..=rect.right() {
-                    ret[y][x] = block.color;
+                    ret[y][x] = self.color_in_block(block, y, x);
                 }
             }
         }
@@ -958,6 +1247,7 @@ impl State {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{isl_parser::load_isl, problem_loader::Problem};
 
     #[test]
     fn test_rect_contain() {
@@ -990,6 +1280,64 @@ mod tests {
         assert_eq!(tl, expected_tl);
     }
 
+    #[test]
+    fn test_cost_model() {
+        let cost_model: CostModel = serde_json::from_str(r#"{"line_cut": 2, "point_cut": 3}"#).unwrap();
+        assert_eq!(
+            cost_model,
+            CostModel {
+                line_cut: 2,
+                point_cut: 3,
+                ..CostModel::default()
+            }
+        );
+        assert_eq!(Command::VerticalSplit(0, 1).base_cost(&cost_model), 2);
+        assert_eq!(Command::Color(0, Color8::default()).base_cost(&cost_model), 5);
+    }
+
+    #[test]
+    fn test_instruction_cost() {
+        let cost_model = CostModel::default();
+        let canvas_size = 400 * 400;
+        let cut = Command::VerticalSplit(0, 1);
+        let color = Command::Color(0, Color8::default());
+        let merge = Command::Merge(0, 1);
+
+        assert_eq!(instruction_cost(&cut, canvas_size, canvas_size, &cost_model), 7);
+        // 7 * 160000 / 60000 = 18.67
+        assert_eq!(instruction_cost(&cut, 400 * 150, canvas_size, &cost_model), 19);
+        // 5 * 160000 / 64000 = 12.5 は切り上げ
+        assert_eq!(instruction_cost(&color, 400 * 160, canvas_size, &cost_model), 13);
+        // 1 * 160000 / 120000 = 1.33
+        assert_eq!(instruction_cost(&merge, 400 * 300, canvas_size, &cost_model), 1);
+
+        let image = Image {
+            height: 400,
+            width: 400,
+            buffer: vec![Color8::default(); canvas_size],
+        };
+        let mut state = State::new(400, 400);
+        state.apply(Command::VerticalSplit(0, 150));
+        state.apply(Command::Color(1, Color8::new(0, 0, 0, 255)));
+        state.apply(Command::VerticalSplit(2, 300));
+        state.apply(Command::Merge(1, 3));
+        // 5 * 160000 / 60000 = 13.3, 7 * 160000 / 100000 = 11.2, 1 * 160000 / 60000 = 2.67
+        assert_eq!(command_cost_list(&image, &state, &cost_model), vec![7, 13, 11, 3]);
+    }
+
+    #[test]
+    fn test_official_score() {
+        // 提出した解と、公式の scorer の仕様どおりに計算したスコア
+        // 類似度は全体で1回、命令コストは命令ごとに丸める
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("..");
+        for (problem_id, expected) in [(2, 6884.0), (3, 19187.0), (4, 17453.0)] {
+            let problem = Problem::load_from_dir(&root.join("dataset"), problem_id);
+            let state = load_isl(&root.join(format!("solution/{}.txt", problem_id)), problem.initial_state()).unwrap();
+            assert_eq!(evaluate(&problem.image, &state, &problem.cost_model), expected);
+            assert_eq!(total_cost(&problem.image, &state, &problem.cost_model), expected);
+        }
+    }
+
     #[test]
     fn test_state_undo() {
         let mut state = State::new(400, 400);
@@ -1017,9 +1365,135 @@ mod tests {
         clone.undo();
         assert_eq!(state, clone);
     }
+
+    #[test]
+    fn test_state_undo_swap_merge() {
+        let mut state = State::new(400, 400);
+        state.apply(Command::PointSplit(0, Pos::new(200, 200)));
+        state.apply(Command::Color(1, Color::new(128, 128, 128, 128)));
+        assert_eq!(state.block_list.len(), 5);
+
+        let mut clone = state.clone();
+        clone.apply(Command::Swap(1, 3));
+        assert_eq!(clone.block_list[1].rect, state.block_list[3].rect);
+        clone.undo();
+        assert_eq!(state, clone);
+
+        clone.apply(Command::Merge(1, 2));
+        assert_eq!(clone.block_list.len(), 6);
+        clone.undo();
+        assert_eq!(state, clone);
+
+        // merge 後の block をさらに操作しても戻せる
+        clone.apply(Command::Merge(1, 2));
+        clone.apply(Command::Merge(3, 4));
+        clone.apply(Command::Merge(5, 6));
+        assert_eq!(clone.block_list.len(), 8);
+        assert_eq!(clone.block_list[7].rect, Rectangle::new(0, 0, 400, 400));
+        for _ in 0..3 {
+            clone.undo();
+        }
+        assert_eq!(state, clone);
+
+        clone.apply(Command::Swap(2, 4));
+        clone.apply(Command::Merge(1, 4));
+        clone.undo();
+        clone.undo();
+        assert_eq!(state, clone);
+    }
+
+    #[test]
+    fn test_state_initial_image() {
+        let mut buffer = vec![];
+        for y in 0..4 {
+            for x in 0..4 {
+                buffer.push(Color8::new(y as u8, x as u8, 0, 255));
+            }
+        }
+        let initial_image = Image { height: 4, width: 4, buffer };
+        let config = config_loader::TwinImageConfig {
+            height: 4,
+            width: 4,
+            blocks: vec![config_loader::BlockConfig {
+                id: 0,
+                rect: Rectangle::new(0, 0, 4, 4),
+                color: Color8::default(),
+                texture: Some(Pos::new(0, 0)),
+            }],
+        };
+        let mut state = State::create_with_initial_image(&config, &initial_image);
+        assert_eq!(state.to_color_buffer()[3][1], Color8::new(3, 1, 0, 255));
+
+        // 左右を入れ替えると画素ごと移動する
+        state.apply(Command::VerticalSplit(1, 2));
+        state.apply(Command::Swap(2, 3));
+        assert_eq!(state.to_color_buffer()[3][1], Color8::new(3, 3, 0, 255));
+        assert_eq!(state.to_color_buffer()[0][2], Color8::new(0, 0, 0, 255));
+
+        let clone = state.clone();
+        state.apply(Command::Color(2, Color8::new(9, 9, 9, 9)));
+        assert_eq!(state.to_color_buffer()[0][2], Color8::new(9, 9, 9, 9));
+        state.undo();
+        assert_eq!(state, clone);
+
+        // 初期画像上で隣り合う並びに戻してから merge する
+        state.apply(Command::Swap(2, 3));
+        state.apply(Command::Merge(2, 3));
+        assert_eq!(state.block_list[4].content, BlockContent::Texture(Pos::new(0, 0)));
+        assert_eq!(state.block_rmse(&initial_image, 4), 0f64);
+        assert_eq!(similarity_cost(&initial_image, &state), 0f64);
+    }
+
+    #[test]
+    fn test_state_merged_content() {
+        let red = Color8::new(255, 0, 0, 255);
+        let blue = Color8::new(0, 0, 255, 255);
+        let mut state = State::new(4, 4);
+        state.apply(Command::VerticalSplit(0, 2));
+        state.apply(Command::Color(1, red));
+        state.apply(Command::Color(2, blue));
+        state.apply(Command::Merge(1, 2));
+        assert_eq!(state.to_color_buffer()[0], vec![red, red, blue, blue]);
+
+        // merge 後のブロックを別の位置で切って入れ替えても、塗った内容ごと移動する
+        state.apply(Command::HorizontalSplit(3, 2));
+        state.apply(Command::VerticalSplit(5, 2));
+        state.apply(Command::VerticalSplit(4, 2));
+        state.apply(Command::Swap(6, 9));
+        let buffer = state.to_color_buffer();
+        assert_eq!(buffer[0], vec![red, red, red, red]);
+        assert_eq!(buffer[3], vec![blue, blue, blue, blue]);
+
+        // 塗ると一色に戻り、undo で元に戻る
+        let clone = state.clone();
+        state.apply(Command::Color(6, blue));
+        assert_eq!(state.to_color_buffer()[0], vec![red, red, blue, blue]);
+        state.undo();
+        assert_eq!(state, clone);
+    }
+
+    #[test]
+    fn test_state_try_apply() {
+        let mut state = State::new(400, 400);
+        state.apply(Command::VerticalSplit(0, 100));
+
+        let clone = state.clone();
+        assert_eq!(state.try_apply(Command::Color(5, Color8::default())), Err(MoveError::UnknownBlock(5)));
+        assert_eq!(state.try_apply(Command::Color(0, Color8::default())), Err(MoveError::DeadBlock(0)));
+        assert_eq!(state.try_apply(Command::VerticalSplit(1, 100)), Err(MoveError::OutOfBoundsCut(1, Rectangle::new(0, 0, 400, 100))));
+        assert_eq!(state.try_apply(Command::HorizontalSplit(1, 0)), Err(MoveError::OutOfBoundsCut(1, Rectangle::new(0, 0, 400, 100))));
+        assert_eq!(state.try_apply(Command::PointSplit(2, Pos::new(200, 50))), Err(MoveError::OutOfBoundsCut(2, Rectangle::new(0, 100, 400, 300))));
+        assert_eq!(state.try_apply(Command::Swap(1, 2)), Err(MoveError::ShapeMismatchedSwap(1, 2)));
+        assert_eq!(state.try_apply(Command::Merge(1, 1)), Err(MoveError::NonAdjacentMerge(1, 1)));
+        assert_eq!(state, clone);
+
+        assert!(state.try_apply(Command::Merge(2, 1)).is_ok());
+        assert_eq!(state.block_list[3].rect, Rectangle::new(0, 0, 400, 400));
+    }
 }
 
-pub fn evaluate(image: &Image, state: &State) -> f64 {
+/// 画像の類似度コスト(丸め済み)
+pub fn similarity_cost(image: &Image, state: &State) -> f64 {
     let mut pixel_cost = 0f64;
     let state_image = state.to_color_buffer();
     for y in 0..image.height {
@@ -1028,14 +1502,38 @@ pub fn evaluate(image: &Image, state: &State) -> f64 {
             pixel_cost += diff.square().horizontal_add().sqrt()
         }
     }
+    (pixel_cost * ALPHA).round()
+}
+
+/// 公式の scorer と同じく、命令ごとに round(base_cost * canvas / block) で丸める
+/// block_size は State::block_size_for_cost で求めたもの
+pub fn instruction_cost(cmd: &Command, block_size: usize, canvas_size: usize, cost_model: &CostModel) -> usize {
+    (cmd.base_cost(cost_model) as f64 * canvas_size as f64 / block_size as f64).round() as usize
+}
 
-    let mut command_cost = 0;
+/// state.command_list の各コマンドのコスト
+pub fn command_cost_list(image: &Image, state: &State, cost_model: &CostModel) -> Vec<usize> {
+    let mut ret = vec![];
     for cmd in state.command_list.iter() {
-        let base_cost = cmd.base_cost();
-        let block_index = cmd.block_index();
-        command_cost += image.size() / state.block_list[block_index].rect.size() * base_cost;
+        let cmd = cmd.to_command();
+        ret.push(instruction_cost(&cmd, state.block_size_for_cost(&cmd), image.size(), cost_model));
     }
-    pixel_cost = (pixel_cost * ALPHA).round();
+    ret
+}
+
+// (類似度コスト, 命令コストの和)
+fn cost_breakdown(image: &Image, state: &State, cost_model: &CostModel) -> (f64, usize) {
+    (similarity_cost(image, state), command_cost_list(image, state, cost_model).iter().sum())
+}
+
+/// evaluate と同じだが、コストを stderr に出さない
+pub fn total_cost(image: &Image, state: &State, cost_model: &CostModel) -> f64 {
+    let (pixel_cost, command_cost) = cost_breakdown(image, state, cost_model);
+    pixel_cost + command_cost as f64
+}
+
+pub fn evaluate(image: &Image, state: &State, cost_model: &CostModel) -> f64 {
+    let (pixel_cost, command_cost) = cost_breakdown(image, state, cost_model);
 
     eprintln!("cost: (pixel, command) = ({}, {})", pixel_cost, command_cost);
 
@@ -1049,39 +1547,26 @@ pub struct StateWithScore {
 }
 
 impl StateWithScore {
-    fn path_of_json(problem_id: usize) -> String {
-        format!("solution/serialized/{}.json", problem_id)
-    }
-
     pub fn load(problem_id: usize) -> Option<StateWithScore> {
-        let path_str = Self::path_of_json(problem_id);
-
-        if let Ok(file) = File::open(Path::new(&path_str)) {
-            let mut reader = BufReader::new(file);
-            serde_json::from_reader(&mut reader).unwrap()
-        } else {
-            None
-        }
+        SolutionStore::default().load(problem_id)
     }
 
+    /// solution/ 以下の最良解より良ければ上書き保存
     pub fn save_if_global_best(&self, problem_id: usize) {
-        if let Some(existing_result) = Self::load(problem_id) {
-            // あったら、既存ファイルをデシリアライズして、スコアがよかったら上書き保存
-            if existing_result.score < self.score {
-                return;
-            }
-        }
-        let path_str = Self::path_of_json(problem_id);
-        let file = File::create(Path::new(&path_str)).unwrap();
-        let mut writer = BufWriter::new(file);
-        serde_json::to_writer_pretty(&mut writer, self).unwrap();
+        SolutionStore::default().save_if_best(problem_id, self);
+    }
 
-        // 画像
-        let image_filepath = format!("solution/img/{}.png", problem_id);
-        self.state.save_image(&image_filepath);
+    /// save_if_global_best と同じだが、history には run_info の solver が作った解として残す
+    pub fn save_if_global_best_as(&self, problem_id: usize, run_info: &RunInfo) {
+        SolutionStore::default().save_if_best_as(problem_id, self, run_info);
+    }
+
+    /// save_if_global_best と同じだが、最良解を更新しなくても history に残す
+    pub fn save_final(&self, problem_id: usize) {
+        SolutionStore::default().save_final(problem_id, self);
+    }
 
-        // 解
-        let solution_filepath = format!("solution/{}.txt", problem_id);
-        self.state.print_output(Path::new(&solution_filepath));
+    pub fn save_final_as(&self, problem_id: usize, run_info: &RunInfo) {
+        SolutionStore::default().save_final_as(problem_id, self, run_info);
     }
 }
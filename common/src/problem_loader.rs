@@ -0,0 +1,120 @@
+use std::path::{Path, PathBuf};
+
+use crate::{
+    config_loader::TwinImageConfig,
+    isl_parser::apply_isl,
+    problem::{CostModel, Image, State},
+};
+
+/// 1問分の入力一式
+/// dataset_dir 以下の {id}.png, {id}.initial.json, {id}.initial.png, {id}.cost.json を読む
+pub struct Problem {
+    pub id: usize,
+    // 目標の画像
+    pub image: Image,
+    // 初期状態のブロック (26..40 のみ)
+    pub config: Option<TwinImageConfig>,
+    // 初期状態の画像 (26..40 のみ. ブロックが画像を表示するのは 36..40)
+    pub initial_image: Option<Image>,
+    pub cost_model: CostModel,
+}
+
+impl Problem {
+    pub fn load(problem_id: usize) -> Problem {
+        Problem::load_from_dir(Path::new("dataset"), problem_id)
+    }
+
+    pub fn load_from_dir(dataset_dir: &Path, problem_id: usize) -> Problem {
+        let path_of = |suffix: &str| -> PathBuf { dataset_dir.join(format!("{}{}", problem_id, suffix)) };
+
+        let image_path = path_of(".png");
+        let image = Image::new(image_path.to_str().unwrap());
+
+        let config_path = path_of(".initial.json");
+        let config = if config_path.exists() { Some(TwinImageConfig::load(&config_path)) } else { None };
+
+        let initial_image_path = path_of(".initial.png");
+        let initial_image = if initial_image_path.exists() {
+            Some(Image::new(initial_image_path.to_str().unwrap()))
+        } else {
+            None
+        };
+
+        let cost_model_path = path_of(".cost.json");
+        let cost_model = if cost_model_path.exists() { CostModel::load_from(&cost_model_path) } else { CostModel::default() };
+
+        Problem {
+            id: problem_id,
+            image,
+            config,
+            initial_image,
+            cost_model,
+        }
+    }
+
+    /// 初期状態のブロックがあればそれを、なければ白紙の canvas を返す
+    pub fn initial_state(&self) -> State {
+        match (&self.config, &self.initial_image) {
+            (Some(config), Some(initial_image)) => State::create_with_initial_image(config, initial_image),
+            (Some(config), None) => State::create_with_config(config),
+            _ => State::new(self.image.height, self.image.width),
+        }
+    }
+
+    /// 白紙の canvas (State::new) から作った解を、この問題の本当の初期状態から再生し直す
+    /// 初期ブロックが canvas 全体の 1 つだけの問題 (36..40) は、白紙とブロック id が同じなので ISL をそのまま使える
+    /// それ以外の問題や、すでに初期状態から作った解はそのまま返す
+    pub fn replay_on_initial_state(&self, state: &State) -> State {
+        let single_root = self.config.as_ref().is_some_and(|config| config.blocks.len() == 1);
+        if !single_root || state.initial_block_count() != 1 {
+            return state.clone();
+        }
+        apply_isl(&state.to_isl(), self.initial_state()).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::problem::{Color8, Command};
+
+    fn dataset_dir() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("../dataset")
+    }
+
+    #[test]
+    fn test_load_problem() {
+        let problem = Problem::load_from_dir(&dataset_dir(), 1);
+        assert!(problem.config.is_none());
+        assert_eq!(problem.initial_state(), State::new(400, 400));
+
+        let problem = Problem::load_from_dir(&dataset_dir(), 26);
+        assert!(problem.config.is_some());
+        assert_eq!(problem.initial_state().block_list.len(), problem.config.as_ref().unwrap().blocks.len() + 1);
+
+        let problem = Problem::load_from_dir(&dataset_dir(), 36);
+        assert!(problem.initial_image.is_some());
+        assert!(problem.initial_state().to_color_buffer()[0][0] == problem.initial_image.as_ref().unwrap().color_of(0, 0));
+    }
+
+    #[test]
+    fn test_replay_on_initial_state() {
+        let mut state = State::new(400, 400);
+        state.apply(Command::VerticalSplit(0, 100));
+        state.apply(Command::Color(2, Color8::new(1, 2, 3, 255)));
+
+        // 白紙の問題ではそのまま
+        let problem = Problem::load_from_dir(&dataset_dir(), 1);
+        assert_eq!(problem.replay_on_initial_state(&state), state);
+
+        // 初期画像の問題では、塗っていない所に初期画像が残る
+        let problem = Problem::load_from_dir(&dataset_dir(), 36);
+        let replayed = problem.replay_on_initial_state(&state);
+        assert_eq!(replayed.initial_block_count(), problem.initial_state().initial_block_count());
+        assert_eq!(replayed.to_isl(), state.to_isl());
+        let initial_image = problem.initial_image.as_ref().unwrap();
+        assert!(replayed.to_color_buffer()[0][0] == initial_image.color_of(0, 0));
+        assert!(replayed.to_color_buffer()[0][200] == Color8::new(1, 2, 3, 255));
+        assert_eq!(problem.replay_on_initial_state(&replayed), replayed);
+    }
+}
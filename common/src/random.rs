@@ -24,12 +24,13 @@ impl XorShift {
         if let Some(v) = s0.checked_add(s1) {
             v
         } else {
-            s0 - (std::u64::MAX - s1) - 1
+            s0 - (u64::MAX - s1) - 1
         }
     }
 
+    #[allow(dead_code)]
     pub fn next_float(&mut self) -> f64 {
-        return (self.next() as f64) / (std::u64::MAX as f64);
+        (self.next() as f64) / (u64::MAX as f64)
     }
 }
 
@@ -55,7 +56,7 @@ impl CachedRandom {
             let val = (rand.next() >> 32) as u32;
             ret.int_table.push(val);
 
-            let fval = (val as f64) / (std::u32::MAX as f64);
+            let fval = (val as f64) / (u32::MAX as f64);
             ret.uniform_table.push(fval);
 
             // add eps to avoid log(0)
@@ -80,7 +81,7 @@ impl CachedRandom {
     }
 
     pub fn next_int_range(&mut self, left: u32, right: u32) -> u32 {
-        (((right - left) as u64) * self.next_int() as u64 >> 32) as u32 + left
+        ((((right - left) as u64) * self.next_int() as u64) >> 32) as u32 + left
     }
 
     pub fn next_float(&mut self) -> f64 {
@@ -0,0 +1,402 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    history::{current_run, HistoryEntry, RunInfo},
+    isl_parser::{apply_isl, load_isl},
+    problem::{evaluate, Image, State, StateWithScore},
+    problem_loader::Problem,
+};
+
+/// serialized/{id}.json と history の保存形式
+/// 命令列だけを ISL で持ち、読むときに初期状態から再生する
+/// version のないファイルは State をそのまま書いた昔の形式 (version 1) として読む
+#[derive(serde::Serialize, serde::Deserialize)]
+struct CompactSolution {
+    version: u32,
+    score: f64,
+    height: usize,
+    width: usize,
+    // dataset/{id}.initial.json のブロックから始めたか
+    initial_config: bool,
+    isl: String,
+}
+
+const FORMAT_VERSION: u32 = 2;
+
+/// 問題ごとの最良解を置く場所
+/// root/serialized/{id}.json, root/img/{id}.png, root/{id}.txt の3つを組で更新する
+/// 複数プロセスから同じ問題を同時に更新しても壊れないように、
+/// root/serialized/{id}.lock でロックを取り、一時ファイルに書いてから rename する
+/// 最良解を更新した解は root/history/{id}/ にも追記で残す
+pub struct SolutionStore {
+    root: PathBuf,
+    // 解を読むときに初期状態を作るのに使う
+    dataset_dir: PathBuf,
+}
+
+impl Default for SolutionStore {
+    fn default() -> Self {
+        SolutionStore::new(Path::new("solution"), Path::new("dataset"))
+    }
+}
+
+impl SolutionStore {
+    pub fn new(root: &Path, dataset_dir: &Path) -> SolutionStore {
+        SolutionStore {
+            root: root.to_path_buf(),
+            dataset_dir: dataset_dir.to_path_buf(),
+        }
+    }
+
+    fn path_of_json(&self, problem_id: usize) -> PathBuf {
+        self.root.join(format!("serialized/{}.json", problem_id))
+    }
+
+    fn path_of_image(&self, problem_id: usize) -> PathBuf {
+        self.root.join(format!("img/{}.png", problem_id))
+    }
+
+    fn path_of_isl(&self, problem_id: usize) -> PathBuf {
+        self.root.join(format!("{}.txt", problem_id))
+    }
+
+    fn open_lock(&self, problem_id: usize) -> File {
+        let dir = self.root.join("serialized");
+        std::fs::create_dir_all(&dir).unwrap();
+        File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(dir.join(format!("{}.lock", problem_id)))
+            .unwrap()
+    }
+
+    // ロックは呼び出し側で取る
+    fn read(&self, problem_id: usize) -> Option<StateWithScore> {
+        self.read_solution(problem_id, &self.path_of_json(problem_id))
+    }
+
+    fn read_solution(&self, problem_id: usize, path: &Path) -> Option<StateWithScore> {
+        let file = File::open(path).ok()?;
+        let value: serde_json::Value = serde_json::from_reader(BufReader::new(file)).unwrap();
+        if value.get("version").is_none() {
+            return Some(serde_json::from_value(value).unwrap());
+        }
+
+        let compact: CompactSolution = serde_json::from_value(value).unwrap();
+        assert!(compact.version == FORMAT_VERSION, "{}: unknown format version {}", path.display(), compact.version);
+        let init_state = if compact.initial_config {
+            Problem::load_from_dir(&self.dataset_dir, problem_id).initial_state()
+        } else {
+            State::new(compact.height, compact.width)
+        };
+        let state = apply_isl(&compact.isl, init_state).unwrap_or_else(|err| panic!("{}: {}", path.display(), err));
+        Some(StateWithScore { score: compact.score, state })
+    }
+
+    // 一時ファイルに書くだけ. rename は呼び出し側で行う
+    fn write_solution(path: &Path, result: &StateWithScore) {
+        let canvas = result.state.block_list[0].rect;
+        let compact = CompactSolution {
+            version: FORMAT_VERSION,
+            score: result.score,
+            height: canvas.height,
+            width: canvas.width,
+            initial_config: result.state.initial_block_count() > 1,
+            isl: result.state.to_isl(),
+        };
+        serde_json::to_writer_pretty(BufWriter::new(File::create(path).unwrap()), &compact).unwrap();
+    }
+
+    pub fn load(&self, problem_id: usize) -> Option<StateWithScore> {
+        let lock = self.open_lock(problem_id);
+        lock.lock_shared().unwrap();
+        self.read(problem_id)
+    }
+
+    /// 保存済みの解より良ければ (同点も含む) 上書きして history に残す. 上書きしたら true
+    /// history にはこのスレッドの current_run を solver として記録する
+    pub fn save_if_best(&self, problem_id: usize, result: &StateWithScore) -> bool {
+        self.save(problem_id, result, false, current_run())
+    }
+
+    /// save_if_best と同じだが、history には run_info を記録する
+    /// 別のスレッドで solver が作った解を保存するときに使う
+    pub fn save_if_best_as(&self, problem_id: usize, result: &StateWithScore, run_info: &RunInfo) -> bool {
+        self.save(problem_id, result, false, run_info.clone())
+    }
+
+    /// save_if_best と同じだが、最良解を更新しなくても history には残す
+    /// solver の最終結果を全部残したいときに使う
+    pub fn save_final(&self, problem_id: usize, result: &StateWithScore) -> bool {
+        self.save(problem_id, result, true, current_run())
+    }
+
+    pub fn save_final_as(&self, problem_id: usize, result: &StateWithScore, run_info: &RunInfo) -> bool {
+        self.save(problem_id, result, true, run_info.clone())
+    }
+
+    fn save(&self, problem_id: usize, result: &StateWithScore, archive_always: bool, run_info: RunInfo) -> bool {
+        let lock = self.open_lock(problem_id);
+        lock.lock().unwrap();
+
+        // 同点なら上書きはするが、同じ解が何度も保存されるので history には残さない
+        let existing_score = self.read(problem_id).map(|existing_result| existing_result.score);
+        let overwrite = existing_score.is_none_or(|v| result.score <= v);
+        let improved = existing_score.is_none_or(|v| result.score < v);
+        if overwrite {
+            self.write(problem_id, result);
+        }
+        if improved || archive_always {
+            self.append_history(problem_id, result, improved, run_info);
+        }
+        overwrite
+    }
+
+    // ロックは呼び出し側で取る
+    fn write(&self, problem_id: usize, result: &StateWithScore) {
+        std::fs::create_dir_all(self.root.join("img")).unwrap();
+        let json_path = self.path_of_json(problem_id);
+        let image_path = self.path_of_image(problem_id);
+        let isl_path = self.path_of_isl(problem_id);
+
+        // 全部書き終わってから rename する. スコアの入った json は最後
+        let json_tmp_path = tmp_path_of(&json_path);
+        Self::write_solution(&json_tmp_path, result);
+        let image_tmp_path = tmp_path_of(&image_path);
+        result.state.save_image(&image_tmp_path.to_str().unwrap().to_string());
+        let isl_tmp_path = tmp_path_of(&isl_path);
+        result.state.print_output(&isl_tmp_path);
+
+        std::fs::rename(&isl_tmp_path, &isl_path).unwrap();
+        std::fs::rename(&image_tmp_path, &image_path).unwrap();
+        std::fs::rename(&json_tmp_path, &json_path).unwrap();
+    }
+
+    fn path_of_history(&self, problem_id: usize) -> PathBuf {
+        self.root.join(format!("history/{}", problem_id))
+    }
+
+    // ロックは呼び出し側で取る
+    fn read_history(&self, problem_id: usize) -> Vec<HistoryEntry> {
+        let Ok(file) = File::open(self.path_of_history(problem_id).join("index.jsonl")) else {
+            return vec![];
+        };
+        BufReader::new(file).lines().map(|line| serde_json::from_str(&line.unwrap()).unwrap()).collect()
+    }
+
+    // 解を先に置いてから index に追記するので、index にある解は必ず読める
+    fn append_history(&self, problem_id: usize, result: &StateWithScore, improved: bool, run_info: RunInfo) {
+        let dir = self.path_of_history(problem_id);
+        std::fs::create_dir_all(&dir).unwrap();
+        let entry = HistoryEntry::new(self.read_history(problem_id).len(), result.score, improved, run_info);
+
+        let state_path = dir.join(format!("{}.json", entry.index));
+        let state_tmp_path = tmp_path_of(&state_path);
+        Self::write_solution(&state_tmp_path, result);
+        std::fs::rename(&state_tmp_path, &state_path).unwrap();
+
+        let mut index_file = File::options().create(true).append(true).open(dir.join("index.jsonl")).unwrap();
+        writeln!(index_file, "{}", serde_json::to_string(&entry).unwrap()).unwrap();
+    }
+
+    /// 古い順
+    pub fn history(&self, problem_id: usize) -> Vec<HistoryEntry> {
+        let lock = self.open_lock(problem_id);
+        lock.lock_shared().unwrap();
+        self.read_history(problem_id)
+    }
+
+    pub fn load_history(&self, problem_id: usize, index: usize) -> Option<StateWithScore> {
+        let lock = self.open_lock(problem_id);
+        lock.lock_shared().unwrap();
+        self.read_solution(problem_id, &self.path_of_history(problem_id).join(format!("{}.json", index)))
+    }
+
+    /// history の index 番目の解を、スコアに関係なく最良解として書き戻す
+    pub fn restore(&self, problem_id: usize, index: usize) -> Result<(), String> {
+        let Some(result) = self.load_history(problem_id, index) else {
+            return Err(format!("problem {}: history entry {} not found", problem_id, index));
+        };
+        let lock = self.open_lock(problem_id);
+        lock.lock().unwrap();
+        self.write(problem_id, &result);
+        Ok(())
+    }
+
+    /// 保存済みの ISL, PNG, スコアが食い違っていないか確認する
+    pub fn verify(&self, problem: &Problem) -> Result<(), String> {
+        let problem_id = problem.id;
+        let lock = self.open_lock(problem_id);
+        lock.lock_shared().unwrap();
+
+        let Some(stored) = self.read(problem_id) else {
+            return Err(format!("problem {}: no stored solution", problem_id));
+        };
+        let isl_path = self.path_of_isl(problem_id);
+        if !isl_path.exists() {
+            return Err(format!("problem {}: {} is missing", problem_id, isl_path.display()));
+        }
+        let state = load_isl(&isl_path, problem.initial_state()).map_err(|err| format!("problem {}: {}: {}", problem_id, isl_path.display(), err))?;
+        if state.get_command_list() != stored.state.get_command_list() {
+            return Err(format!("problem {}: ISL and serialized state have different commands", problem_id));
+        }
+
+        let score = evaluate(&problem.image, &state, &problem.cost_model);
+        if (score - stored.score).abs() > 1e-6 {
+            return Err(format!("problem {}: stored score is {} but ISL scores {}", problem_id, stored.score, score));
+        }
+
+        let image_path = self.path_of_image(problem_id);
+        let image = Image::try_new(image_path.to_str().unwrap()).map_err(|err| format!("problem {}: {}", problem_id, err))?;
+        let color_buffer = state.to_color_buffer();
+        if image.height != color_buffer.len() || image.width != color_buffer[0].len() {
+            return Err(format!("problem {}: PNG size differs from the canvas", problem_id));
+        }
+        for y in 0..image.height {
+            for x in 0..image.width {
+                if image.color_of(y, x) != color_buffer[y][x] {
+                    return Err(format!("problem {}: PNG differs from ISL at (x, y) = ({}, {})", problem_id, x, y));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// 同じ問題はロックで直列化されるので、プロセス id だけ付けておけば衝突しない
+fn tmp_path_of(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap().to_os_string();
+    file_name.push(format!(".{}.tmp", std::process::id()));
+    path.with_file_name(file_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        history,
+        problem::{Color8, Command, Pos},
+    };
+
+    fn dataset_dir() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("../dataset")
+    }
+
+    #[test]
+    fn test_solution_store() {
+        let root = std::env::temp_dir().join(format!("solution_store_test_{}", std::process::id()));
+        let store = SolutionStore::new(&root, &dataset_dir());
+        let problem = Problem::load_from_dir(&dataset_dir(), 1);
+        assert!(store.load(1).is_none());
+        assert!(store.verify(&problem).is_err());
+
+        let mut state = State::new(400, 400);
+        state.apply(Command::VerticalSplit(0, 200));
+        state.apply(Command::Color(1, Color8::new(10, 20, 30, 255)));
+        let score = evaluate(&problem.image, &state, &problem.cost_model);
+        assert!(store.save_if_best(1, &StateWithScore { score, state: state.clone() }));
+        assert_eq!(store.load(1).unwrap().score, score);
+        assert!(store.verify(&problem).is_ok());
+
+        // 悪い解では上書きしない
+        let worse = StateWithScore {
+            score: score + 1.0,
+            state: State::new(400, 400),
+        };
+        assert!(!store.save_if_best(1, &worse));
+        assert_eq!(store.load(1).unwrap().state, state);
+
+        // 更新した解だけ history に残る. save_final なら更新しなくても残る
+        assert!(!store.save_final(1, &worse));
+        let history = store.history(1);
+        assert_eq!(history.iter().map(|entry| (entry.index, entry.improved)).collect::<Vec<_>>(), vec![(0, true), (1, false)]);
+        assert_eq!(store.load_history(1, 1).unwrap().score, score + 1.0);
+
+        store.restore(1, 1).unwrap();
+        assert_eq!(store.load(1).unwrap().score, score + 1.0);
+        assert!(store.restore(1, 2).is_err());
+        store.restore(1, 0).unwrap();
+
+        // 別のスレッドで作った解は run_info を明示して保存する
+        history::set_current_run(RunInfo {
+            solver: "other".to_string(),
+            params: String::new(),
+        });
+        let run_info = RunInfo {
+            solver: "winner".to_string(),
+            params: "kind=blank".to_string(),
+        };
+        assert!(store.save_if_best_as(1, &StateWithScore { score: score - 1.0, state: state.clone() }, &run_info));
+        assert!(!store.save_final_as(1, &worse, &run_info));
+        assert!(!store.save_final(1, &worse));
+        let history = store.history(1);
+        assert_eq!(history[2].solver, "winner");
+        assert_eq!(history[2].params, "kind=blank");
+        assert_eq!(history[3].solver, "winner");
+        assert_eq!(history[4].solver, "other");
+
+        // 画像だけ書き換わっていたら検出する
+        State::new(400, 400).save_image(&store.path_of_image(1).to_str().unwrap().to_string());
+        assert!(store.verify(&problem).is_err());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_verify_stored_solution() {
+        // 保存済みの解が検証できる. 初期画像のある問題 (36) は初期状態から再生する
+        let root = std::env::temp_dir().join(format!("solution_store_verify_test_{}", std::process::id()));
+        let solution_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../solution");
+        for problem_id in [2, 36] {
+            for path in [format!("serialized/{}.json", problem_id), format!("img/{}.png", problem_id), format!("{}.txt", problem_id)] {
+                std::fs::create_dir_all(root.join(&path).parent().unwrap()).unwrap();
+                std::fs::copy(solution_dir.join(&path), root.join(&path)).unwrap();
+            }
+            let store = SolutionStore::new(&root, &dataset_dir());
+            let problem = Problem::load_from_dir(&dataset_dir(), problem_id);
+            assert_eq!(store.verify(&problem), Ok(()));
+            assert_eq!(store.load(problem_id).unwrap().state.initial_block_count(), problem.initial_state().initial_block_count());
+        }
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_compact_format() {
+        let root = std::env::temp_dir().join(format!("solution_store_format_test_{}", std::process::id()));
+        let store = SolutionStore::new(&root, &dataset_dir());
+
+        // 命令列を再生して同じ State に戻る
+        let mut state = State::new(400, 400);
+        state.apply(Command::VerticalSplit(0, 100));
+        state.apply(Command::Color(2, Color8::new(1, 2, 3, 4)));
+        state.apply(Command::PointSplit(1, Pos::new(200, 50)));
+        state.apply(Command::Swap(3, 5));
+        state.apply(Command::Merge(6, 5));
+        store.save_if_best(1, &StateWithScore { score: 1.0, state: state.clone() });
+        let text = std::fs::read_to_string(store.path_of_json(1)).unwrap();
+        assert!(text.contains("\"version\": 2"));
+        assert_eq!(store.load(1).unwrap().state, state);
+
+        let problem = Problem::load_from_dir(&dataset_dir(), 26);
+        let mut state = problem.initial_state();
+        let block_index = state.block_list.iter().position(|block| block.is_child).unwrap();
+        state.apply(Command::Color(block_index, Color8::new(1, 2, 3, 4)));
+        store.save_if_best(26, &StateWithScore { score: 1.0, state: state.clone() });
+        assert_eq!(store.load(26).unwrap().state, state);
+
+        // version のない昔の形式も読める
+        let legacy = StateWithScore { score: 0.5, state: state.clone() };
+        serde_json::to_writer_pretty(File::create(store.path_of_json(26)).unwrap(), &legacy).unwrap();
+        let loaded = store.load(26).unwrap();
+        assert_eq!(loaded.score, 0.5);
+        assert_eq!(loaded.state.get_command_list(), state.get_command_list());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -3,17 +3,8 @@
 set -eu
 
 cargo build --release --bin solver
-cp target/release/solver ./solver_bin
 
-# single experiment
-dataset="`seq 1 25` `seq 36 40`"
-echo $dataset
-parallel --progress --result result ./solver_bin -i {} -s 6 ::: $dataset &
-
-# twin experiment
-dataset_twin=`seq 26 35`
-parallel --progress ./solver_bin -i {} -s 9 -t ::: $dataset_twin &
-
-wait
-
-rm ./solver_bin
+# 問題ごとに使える solver を全部試して、一番良いものを残す
+# 勝った solver は solution/portfolio/{id}.json, 全体の結果は solution/batch/report.json に残る
+# divisor-climb などは終わらないので、時間で打ち切る
+./target/release/solver batch -i 1-40 --parallel --time-limit 1800
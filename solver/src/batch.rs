@@ -0,0 +1,184 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::BufWriter,
+    path::Path,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::{deadline::Deadline, problem::StateWithScore, problem_loader::Problem};
+
+use crate::{
+    portfolio,
+    solver::{ProblemKind, SolverContext},
+};
+
+pub struct BatchConfig {
+    pub problem_id_list: Vec<usize>,
+    // カンマ区切り. None なら使える solver 全部
+    pub solver_names: Option<String>,
+    pub thread_count: usize,
+    // 問題ごとの制限時間
+    pub time_limit: Option<Duration>,
+    // 問題の中でも solver ごとにスレッドを立てる
+    pub parallel_solvers: bool,
+    // solver の最終結果を全部 history に残す
+    pub archive_final: bool,
+}
+
+/// 1問分の結果
+#[derive(serde::Serialize)]
+pub struct BatchEntry {
+    pub problem_id: usize,
+    // 今回一番良かった solver. 使える solver がなければ None
+    pub solver: Option<String>,
+    pub score: Option<f64>,
+    pub old_best: Option<f64>,
+    pub new_best: Option<f64>,
+    pub wall_time_sec: f64,
+}
+
+impl BatchEntry {
+    pub fn delta(&self) -> Option<f64> {
+        match (self.old_best, self.new_best) {
+            (Some(old_best), Some(new_best)) => Some(new_best - old_best),
+            _ => None,
+        }
+    }
+}
+
+/// "1-25,36,38-40" 形式の問題 id 列
+pub fn parse_problem_id_list(text: &str) -> Result<Vec<usize>, String> {
+    let mut ret = vec![];
+    for range in text.split(',') {
+        let range = range.trim();
+        let parse = |v: &str| v.trim().parse::<usize>().map_err(|_| format!("invalid problem id range '{}'", range));
+        if let Some((from, to)) = range.split_once('-') {
+            let (from, to) = (parse(from)?, parse(to)?);
+            if from > to {
+                return Err(format!("invalid problem id range '{}'", range));
+            }
+            ret.extend(from..=to);
+        } else {
+            ret.push(parse(range)?);
+        }
+    }
+    Ok(ret)
+}
+
+fn solve_problem(problem_id: usize, config: &BatchConfig) -> BatchEntry {
+    let start = Instant::now();
+    let problem = Problem::load(problem_id);
+    let context = SolverContext {
+        kind: ProblemKind::of(&problem),
+        deadline: Deadline::new(config.time_limit),
+        archive_final: config.archive_final,
+    };
+    let old_best = StateWithScore::load(problem_id).map(|v| v.score);
+
+    // solver 名は事前に確認済みなので、ここでは使えないものを除くだけ
+    let solver_list = portfolio::select_solvers(&problem, &context, config.solver_names.as_deref(), false).unwrap();
+    let result = if config.parallel_solvers {
+        portfolio::run_parallel(Arc::new(problem), solver_list, &context)
+    } else {
+        portfolio::run_sequential(&problem, solver_list, &context)
+    };
+    result.save_report(problem_id);
+
+    let best = result.best();
+    if let Some(best) = best {
+        StateWithScore {
+            score: best.score,
+            state: best.state.clone(),
+        }
+        .save_if_global_best_as(problem_id, &best.run_info);
+    }
+    let score = best.map(|entry| entry.score);
+    let new_best = match (old_best, score) {
+        (Some(old_best), Some(score)) => Some(old_best.min(score)),
+        (old_best, score) => old_best.or(score),
+    };
+
+    BatchEntry {
+        problem_id,
+        solver: best.map(|entry| entry.solver_name.clone()),
+        score,
+        old_best,
+        new_best,
+        wall_time_sec: (Instant::now() - start).as_secs_f64(),
+    }
+}
+
+/// thread_count 個のスレッドで問題を順に取り出して解く
+/// 結果は problem id 順
+pub fn run_batch(config: BatchConfig) -> Vec<BatchEntry> {
+    let config = Arc::new(config);
+    let queue = Arc::new(Mutex::new(config.problem_id_list.iter().copied().collect::<VecDeque<_>>()));
+
+    let (sender, receiver) = mpsc::channel();
+    let mut handle_list = vec![];
+    for _ in 0..config.thread_count.max(1) {
+        let config = config.clone();
+        let queue = queue.clone();
+        let sender = sender.clone();
+        handle_list.push(thread::spawn(move || loop {
+            let Some(problem_id) = queue.lock().unwrap().pop_front() else {
+                break;
+            };
+            sender.send(solve_problem(problem_id, &config)).unwrap();
+        }));
+    }
+    drop(sender);
+
+    let mut entry_list = vec![];
+    for entry in receiver.iter() {
+        eprintln!("problem {} done: {:?} by {:?}", entry.problem_id, entry.score, entry.solver);
+        entry_list.push(entry);
+    }
+    for handle in handle_list.into_iter() {
+        handle.join().unwrap();
+    }
+    entry_list.sort_by_key(|entry| entry.problem_id);
+    entry_list
+}
+
+pub fn print_summary(entry_list: &[BatchEntry]) {
+    let format_score = |v: Option<f64>| v.map_or("-".to_string(), |v| format!("{}", v));
+    println!("{:>7} {:<18} {:>10} {:>10} {:>10} {:>9}", "problem", "solver", "old best", "new best", "delta", "wall time");
+    for entry in entry_list.iter() {
+        println!(
+            "{:>7} {:<18} {:>10} {:>10} {:>10} {:>8.1}s",
+            entry.problem_id,
+            entry.solver.as_deref().unwrap_or("-"),
+            format_score(entry.old_best),
+            format_score(entry.new_best),
+            format_score(entry.delta()),
+            entry.wall_time_sec
+        );
+    }
+    let total_delta: f64 = entry_list.iter().filter_map(|entry| entry.delta()).sum();
+    println!("total delta: {}", total_delta);
+}
+
+pub fn save_report(entry_list: &[BatchEntry], filepath: &Path) {
+    if let Some(dir) = filepath.parent() {
+        std::fs::create_dir_all(dir).unwrap();
+    }
+    let writer = BufWriter::new(File::create(filepath).unwrap());
+    serde_json::to_writer_pretty(writer, entry_list).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_problem_id_list() {
+        assert_eq!(parse_problem_id_list("1-3,36, 38-40").unwrap(), vec![1, 2, 3, 36, 38, 39, 40]);
+        assert_eq!(parse_problem_id_list("5").unwrap(), vec![5]);
+        assert!(parse_problem_id_list("3-1").is_err());
+        assert!(parse_problem_id_list("1,a").is_err());
+    }
+}
@@ -1,25 +1,191 @@
-use common::cumulative_sum::RangeColorMedianCalculator;
+use common::cumulative_sum::{CumulativeRMSESum, CumulativeSum};
+use common::deadline::Deadline;
 use common::problem::*;
-use std::collections::VecDeque;
+use common::optimizer::geometric_median;
+use std::collections::{HashMap, VecDeque};
 
 // ブロック番号が確定しないので、メモ用途だけのコマンド
+// 表を小さくするため、位置は u16 で持つ
 #[derive(Clone, Copy, Debug)]
 enum SimpleCommand {
     // xi
-    VerticalSplit(usize),
+    VerticalSplit(u16),
     // yi
-    HorizontalSplit(usize),
+    HorizontalSplit(u16),
     // yi, xi
-    PointSplit(usize, usize),
-    Color(Color8),
+    PointSplit(u16, u16),
+    Color,
+}
+
+// 行動復元用の情報
+#[derive(Clone, Copy, Debug)]
+struct Choice {
+    // このタイルが行うべきコマンド
+    cmd: SimpleCommand,
+    // 子供の何番目の色を塗るか. SimpleCommand::Color だった場合は意味のないデータになる
+    child_index: u8,
+}
+
+/// [row_list[y1], row_list[y2]) x [column_list[x1], column_list[x2]) の長方形を平らな配列の添字にする
+/// y1 < y2, x1 < x2 の組だけを詰める
+struct RectIndexer {
+    column_pair_count: usize,
+}
+
+impl RectIndexer {
+    fn pair_index(v1: usize, v2: usize) -> usize {
+        v2 * (v2 - 1) / 2 + v1
+    }
+
+    fn pair_count(len: usize) -> usize {
+        len * (len - 1) / 2
+    }
+
+    fn index(&self, y1: usize, x1: usize, y2: usize, x2: usize) -> usize {
+        RectIndexer::pair_index(y1, y2) * self.column_pair_count + RectIndexer::pair_index(x1, x2)
+    }
+}
+
+/// セル単位の長方形 [y1, y2) x [x1, x2) を1色で塗るときの色と、類似度コスト (距離の和) を求める
+/// 画素はセルごとに同じ色をまとめておき、長方形の中のセルの分だけを見る
+/// セルは行優先に並べるので、1行分のセルは配列上で連続している
+struct GridColorTable {
+    column_count: usize,
+    // セルごとの (色, 画素数). 距離の和はこちらで正確に求める
+    color_list: Vec<(Color8, u32)>,
+    color_offset_list: Vec<usize>,
+    // セルごとに成分を 16 段階に丸めた (平均色, 画素数). 幾何中央値の反復はこちらで回す
+    bucket_list: Vec<(Color64, f64)>,
+    bucket_offset_list: Vec<usize>,
+    // histogram_list[channel * 256 + value] := その成分が value の画素数の、セル単位の累積和
+    histogram_list: Vec<CumulativeSum<usize>>,
+}
+
+impl GridColorTable {
+    const MAX_ITERATION: usize = 5;
+
+    fn new(image: &Image, row_list: &[usize], column_list: &[usize]) -> GridColorTable {
+        let (row_count, column_count) = (row_list.len() - 1, column_list.len() - 1);
+        let (mut color_list, mut color_offset_list) = (vec![], vec![0]);
+        let (mut bucket_list, mut bucket_offset_list) = (vec![], vec![0]);
+        let mut count_table = vec![vec![vec![0; column_count]; row_count]; 4 * 256];
+        for yi in 0..row_count {
+            for xi in 0..column_count {
+                let mut color_map = HashMap::new();
+                let mut bucket_map = HashMap::new();
+                for y in row_list[yi]..row_list[yi + 1] {
+                    for x in column_list[xi]..column_list[xi + 1] {
+                        let c = image.color_of(y, x);
+                        *color_map.entry(u32::from_be_bytes([c.r, c.g, c.b, c.a])).or_insert(0) += 1;
+                        let bucket = bucket_map.entry(u32::from_be_bytes([c.r >> 4, c.g >> 4, c.b >> 4, c.a >> 4])).or_insert((Color64::default(), 0.0));
+                        bucket.0 += c.to64();
+                        bucket.1 += 1.0;
+                        for (channel, value) in [c.r, c.g, c.b, c.a].into_iter().enumerate() {
+                            count_table[channel * 256 + value as usize][yi][xi] += 1;
+                        }
+                    }
+                }
+                color_list.extend(color_map.into_iter().map(|(key, count)| {
+                    let [r, g, b, a] = u32::to_be_bytes(key);
+                    (Color8::new(r, g, b, a), count)
+                }));
+                color_offset_list.push(color_list.len());
+                bucket_list.extend(bucket_map.into_values().map(|(sum, count)| (sum / count, count)));
+                bucket_offset_list.push(bucket_list.len());
+            }
+        }
+        GridColorTable {
+            column_count,
+            color_list,
+            color_offset_list,
+            bucket_list,
+            bucket_offset_list,
+            histogram_list: count_table.iter().map(CumulativeSum::new).collect(),
+        }
+    }
+
+    /// 成分ごとの中央値 (小さい方から ceil(n / 2) 番目)
+    fn median(&self, y1: usize, x1: usize, y2: usize, x2: usize, pixel_count: usize) -> Color64 {
+        let threashold = pixel_count.div_ceil(2);
+        let [r, g, b, a] = [0, 1, 2, 3].map(|channel| {
+            let mut sum = 0;
+            for value in 0..256 {
+                sum += self.histogram_list[channel * 256 + value].range_sum(y1, x1, y2, x2);
+                if sum >= threashold {
+                    return value as f64;
+                }
+            }
+            255.0
+        });
+        Color64::new(r, g, b, a)
+    }
+
+    fn color_cost(&self, y1: usize, x1: usize, y2: usize, x2: usize) -> (Color8, f64) {
+        let row_range = move |offset_list: &[usize], yi: usize| offset_list[yi * self.column_count + x1]..offset_list[yi * self.column_count + x2];
+        let bucket_iter = (y1..y2).flat_map(|yi| self.bucket_list[row_range(&self.bucket_offset_list, yi)].iter().copied());
+
+        // 成分ごとの中央値と、そこから丸めた色の上で少しだけ反復した幾何中央値の良い方を使う
+        let pixel_count = bucket_iter.clone().map(|(_, count)| count).sum::<f64>() as usize;
+        let median = self.median(y1, x1, y2, x2, pixel_count);
+        let geometric = geometric_median(bucket_iter, median, Self::MAX_ITERATION).round();
+
+        // 距離の和は一番重いところなので、イテレータを重ねずに回す
+        let (mut median_cost, mut geometric_cost) = (0.0, 0.0);
+        for yi in y1..y2 {
+            for (c, count) in self.color_list[row_range(&self.color_offset_list, yi)].iter() {
+                let (p, count) = (c.to64(), *count as f64);
+                median_cost += (p - median).square().horizontal_add().sqrt() * count;
+                geometric_cost += (p - geometric).square().horizontal_add().sqrt() * count;
+            }
+        }
+        if geometric_cost < median_cost {
+            (geometric.to8(), geometric_cost)
+        } else {
+            (median.to8(), median_cost)
+        }
+    }
+}
+
+/// 長方形を1色で塗るときの類似度コストの求め方
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RectCost {
+    // 成分ごとの中央値か幾何中央値で塗ったときの距離の和を、セルごとにまとめた色から正確に求める
+    Exact,
+    // 平均色で塗ったときの sqrt(画素数 * 二乗誤差) で近似する. 累積和で O(1)
+    // 行・列が 50 本を超えるときはこちらでないと終わらない
+    Approximate,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DivisorOptions {
+    pub rect_cost: RectCost,
+    pub thread_count: usize,
+}
+
+impl Default for DivisorOptions {
+    fn default() -> Self {
+        DivisorOptions {
+            rect_cost: RectCost::Exact,
+            thread_count: 1,
+        }
+    }
 }
 
 /// 縦分割、横分割、十字分割を試して、色の塗り方は愚直に色々試す
-/// table_height, table_width それぞれ 50 位が限界？
 /// 端点に 0, と image.height / image.width を入れてください…
-pub fn solve_by_divisor(image: &Image, row_list: &Vec<usize>, column_list: &Vec<usize>) -> State {
-    const INF_COST: f64 = std::f64::MAX;
+/// deadline を過ぎると、残りの長方形は近似した色のコストと線分割だけで埋めて、すぐに解を作る
+pub fn solve_by_divisor(image: &Image, cost_model: &CostModel, row_list: &Vec<usize>, column_list: &Vec<usize>, deadline: &Deadline) -> State {
+    solve_by_divisor_with(image, cost_model, row_list, column_list, deadline, &DivisorOptions::default())
+}
 
+/// 同じ大きさ (行数, 列数) の長方形は互いに依存しないので、小さい方から大きさごとに options.thread_count 並列で埋める
+/// 塗る色はどちらの RectCost でも、復元するときに GridColorTable で決める
+pub fn solve_by_divisor_with(image: &Image, cost_model: &CostModel, row_list: &Vec<usize>, column_list: &Vec<usize>, deadline: &Deadline, options: &DivisorOptions) -> State {
+    solve_by_divisor_with_cost(image, cost_model, row_list, column_list, deadline, options).0
+}
+
+/// 復元した解と、DP 表の上での (丸める前の) 全体のコスト
+fn solve_by_divisor_with_cost(image: &Image, cost_model: &CostModel, row_list: &Vec<usize>, column_list: &Vec<usize>, deadline: &Deadline, options: &DivisorOptions) -> (State, f64) {
     for yi in 0..row_list.len() - 1 {
         assert!(row_list[yi] < row_list[yi + 1]);
     }
@@ -30,196 +196,210 @@ pub fn solve_by_divisor(image: &Image, row_list: &Vec<usize>, column_list: &Vec<
     assert!(*row_list.last().unwrap() == image.height);
     assert!(column_list[0] == 0);
     assert!(*column_list.last().unwrap() == image.width);
+    assert!(row_list.len() <= u16::MAX as usize && column_list.len() <= u16::MAX as usize);
 
     let table_height = row_list.len();
     let table_width = column_list.len();
-
-    // DP[y1][x1][y2][x2] := [row_list[y1], row_list[y2]), [col_list[x1], col_list[x2]) の領域を生コスト最小で塗る方法
-    let mut dp = vec![vec![vec![vec![INF_COST; table_width]; table_height]; table_width]; table_height];
-    // 行動復元用のコマンド
-    let mut restore_table = vec![vec![vec![vec![None; table_width]; table_height]; table_width]; table_height];
-
-    let median_calculator = RangeColorMedianCalculator::new(image);
-
-    fn inner(
-        image: &Image,
-        median_calculator: &RangeColorMedianCalculator,
-        height: usize,
-        width: usize,
-        dp: &mut Vec<Vec<Vec<Vec<f64>>>>,
-        // (このタイルが行うべきコマンド, 子供の何番目の色を塗るか, 何色で塗るか)
-        // SimpleCommand::Color だった場合は意味のないデータになる
-        restore_table: &mut Vec<Vec<Vec<Vec<Option<(SimpleCommand, usize, Color8)>>>>>,
-        row_list: &Vec<usize>,
-        column_list: &Vec<usize>,
-        y1: usize,
-        x1: usize,
-        y2: usize,
-        x2: usize,
-    ) {
-        assert!(y1 <= y2);
-        assert!(x1 <= x2);
-
-        let canvas_size = height * width;
-
-        let calculate_block_size = |y1: usize, x1: usize, y2: usize, x2: usize| -> usize { (row_list[y2] - row_list[y1]) * (column_list[x2] - column_list[x1]) };
-
-        let calculate_color_cost = |y1: usize, x1: usize, y2: usize, x2: usize| -> (f64, Color8) {
-            let sy = row_list[y1];
-            let sx = column_list[x1];
-            let ey = row_list[y2];
-            let ex = column_list[x2];
-
-            // 画素値が大体同じ値だと仮定すると、sqrt(n) で割る位がちょうどよさそう
-            let color = median_calculator.median(sy, sx, ey, ex);
-            let color64 = color.to64();
-            let mut rmse_sum = 0.0;
-            for y in sy..ey {
-                for x in sx..ex {
-                    rmse_sum += (image.color_of(y, x).to64() - color64).square().horizontal_add().sqrt();
-                }
-            }
-
-            let block_size = calculate_block_size(y1, x1, y2, x2);
-            let command_cost = COLOR_COST * canvas_size as f64 / block_size as f64;
-
-            // FIXME: 後で直した方がいいかも？
-            (rmse_sum * ALPHA + command_cost, color)
+    let indexer = RectIndexer {
+        column_pair_count: RectIndexer::pair_count(table_width),
+    };
+    let rect_count = RectIndexer::pair_count(table_height) * indexer.column_pair_count;
+
+    // dp[indexer.index(y1, x1, y2, x2)] := その領域を生コスト最小で塗るコスト
+    let mut dp = vec![f64::MAX; rect_count];
+    let mut choice_table = vec![
+        Choice {
+            cmd: SimpleCommand::Color,
+            child_index: 0,
         };
-
-        let calculate_line_cut_cost = |y1: usize, x1: usize, y2: usize, x2: usize| -> f64 {
-            let canvas_size = height * width;
-            let block_size = calculate_block_size(y1, x1, y2, x2);
-            LINE_CUT_COST * canvas_size as f64 / block_size as f64
+        rect_count
+    ];
+
+    let rmse_sum = CumulativeRMSESum::new(image);
+    let grid_color = GridColorTable::new(image, row_list, column_list);
+    let canvas_size = image.height * image.width;
+    // コストは命令の種類にしか依存しないので、引数はダミー
+    let color_cost_of = |block_size: usize| -> f64 { instruction_cost(&Command::Color(0, Color8::default()), block_size, canvas_size, cost_model) as f64 };
+    let line_cut_cost_of = |block_size: usize| -> f64 { instruction_cost(&Command::VerticalSplit(0, 0), block_size, canvas_size, cost_model) as f64 };
+    let point_cut_cost_of = |block_size: usize| -> f64 { instruction_cost(&Command::PointSplit(0, Pos::new(0, 0)), block_size, canvas_size, cost_model) as f64 };
+
+    let calculate_block_size = |y1: usize, x1: usize, y2: usize, x2: usize| -> usize { (row_list[y2] - row_list[y1]) * (column_list[x2] - column_list[x1]) };
+
+    let calculate_color_cost = |y1: usize, x1: usize, y2: usize, x2: usize, rect_cost: RectCost| -> f64 {
+        let (sy, sx, ey, ex) = (row_list[y1], column_list[x1], row_list[y2], column_list[x2]);
+        let block_size = calculate_block_size(y1, x1, y2, x2);
+        let pixel_cost = match rect_cost {
+            RectCost::Exact => grid_color.color_cost(y1, x1, y2, x2).1,
+            // 距離の和は累積和で求まらないので、二乗誤差から近似する
+            // 画素値が大体同じ値だと仮定すると、sqrt(n) 倍する位がちょうどよさそう
+            RectCost::Approximate => (rmse_sum.range_rmse(sy, sx, ey, ex).horizontal_add().max(0.0) * block_size as f64).sqrt(),
         };
+        pixel_cost * ALPHA + color_cost_of(block_size)
+    };
 
-        let calculate_point_cut_cost = |y1: usize, x1: usize, y2: usize, x2: usize| -> f64 {
-            let canvas_size = height * width;
-            let block_size = calculate_block_size(y1, x1, y2, x2);
-            POINT_CUT_COST * canvas_size as f64 / block_size as f64
+    // State の分割は上・右の子の幅が 2 以上でないと切れない
+    let can_split_row = |yi: usize, y2: usize| row_list[y2] - row_list[yi] >= 2;
+    let can_split_column = |xi: usize, x2: usize| column_list[x2] - column_list[xi] >= 2;
+
+    // 小さい長方形が全部埋まっている前提で、1つの長方形の最適な塗り方を求める
+    // expired なら色のコストは近似で求め、点分割は試さない
+    let solve_rect = |dp: &Vec<f64>, y1: usize, x1: usize, y2: usize, x2: usize, expired: bool| -> (f64, Choice) {
+        let mut best_cost = calculate_color_cost(y1, x1, y2, x2, if expired { RectCost::Approximate } else { options.rect_cost });
+        // 後ろは意味ない
+        let mut best_choice = Choice {
+            cmd: SimpleCommand::Color,
+            child_index: 0,
         };
-
-        // 答えが埋まっている場合はそれを返す
-        if dp[y1][x1][y2][x2] != INF_COST {
-            return;
-        }
-
-        // そのまま色を塗るコストを計算
-        let (color_cost, color) = calculate_color_cost(y1, x1, y2, x2);
-        if dp[y1][x1][y2][x2] > color_cost {
-            dp[y1][x1][y2][x2] = color_cost;
-            // 後ろ2つは意味ない
-            restore_table[y1][x1][y2][x2] = Some((SimpleCommand::Color(color), 0, color));
-        }
         let self_block_size = calculate_block_size(y1, x1, y2, x2);
+        let self_color_cost = color_cost_of(self_block_size);
+        let line_cut_cost = line_cut_cost_of(self_block_size);
+        let point_cut_cost = point_cut_cost_of(self_block_size);
 
-        // 横分割して再帰
+        // 横分割
         for yi in y1 + 1..y2 {
-            inner(image, median_calculator, height, width, dp, restore_table, row_list, column_list, y1, x1, yi, x2);
-            inner(image, median_calculator, height, width, dp, restore_table, row_list, column_list, yi, x1, y2, x2);
+            if !can_split_row(yi, y2) {
+                continue;
+            }
+            let (child1, child2) = (indexer.index(y1, x1, yi, x2), indexer.index(yi, x1, y2, x2));
 
             // 色の塗り方を工夫することで、自分の色を塗ってから最大コストの色塗りを1つ回避できる
             let block_size1 = calculate_block_size(y1, x1, yi, x2);
             let block_size2 = calculate_block_size(yi, x1, y2, x2);
-            let max_color_cost = COLOR_COST * canvas_size as f64 / block_size1.min(block_size2) as f64;
-            let self_color_cost = COLOR_COST * canvas_size as f64 / self_block_size as f64;
+            let max_color_cost = color_cost_of(block_size1.min(block_size2));
 
-            let vert_cost = dp[y1][x1][yi][x2] + dp[yi][x1][y2][x2] + calculate_line_cut_cost(y1, x1, y2, x2) + self_color_cost - max_color_cost;
-            if dp[y1][x1][y2][x2] > vert_cost {
-                dp[y1][x1][y2][x2] = vert_cost;
+            let vert_cost = dp[child1] + dp[child2] + line_cut_cost + self_color_cost - max_color_cost;
+            if best_cost > vert_cost {
+                best_cost = vert_cost;
 
-                let (child_index, child_color) = if block_size1 < block_size2 {
-                    (0, restore_table[y1][x1][yi][x2].unwrap().2)
-                } else {
-                    (1, restore_table[yi][x1][y2][x2].unwrap().2)
+                best_choice = Choice {
+                    cmd: SimpleCommand::HorizontalSplit(yi as u16),
+                    child_index: if block_size1 < block_size2 { 0 } else { 1 },
                 };
-                restore_table[y1][x1][y2][x2] = Some((SimpleCommand::HorizontalSplit(yi), child_index, child_color));
             }
         }
 
-        // 縦分割して再帰
+        // 縦分割
         for xi in x1 + 1..x2 {
-            inner(image, median_calculator, height, width, dp, restore_table, row_list, column_list, y1, x1, y2, xi);
-            inner(image, median_calculator, height, width, dp, restore_table, row_list, column_list, y1, xi, y2, x2);
+            if !can_split_column(xi, x2) {
+                continue;
+            }
+            let (child1, child2) = (indexer.index(y1, x1, y2, xi), indexer.index(y1, xi, y2, x2));
 
-            // 色の塗り方を工夫することで、自分の色を塗ってから最大コストの色塗りを1つ回避できる
             let block_size1 = calculate_block_size(y1, x1, y2, xi);
             let block_size2 = calculate_block_size(y1, xi, y2, x2);
-            let max_color_cost = COLOR_COST * canvas_size as f64 / block_size1.min(block_size2) as f64;
-            let self_color_cost = COLOR_COST * canvas_size as f64 / self_block_size as f64;
+            let max_color_cost = color_cost_of(block_size1.min(block_size2));
 
-            let hor_cost = dp[y1][x1][y2][xi] + dp[y1][xi][y2][x2] + calculate_line_cut_cost(y1, x1, y2, x2) + self_color_cost - max_color_cost;
-            if dp[y1][x1][y2][x2] > hor_cost {
-                dp[y1][x1][y2][x2] = hor_cost;
+            let hor_cost = dp[child1] + dp[child2] + line_cut_cost + self_color_cost - max_color_cost;
+            if best_cost > hor_cost {
+                best_cost = hor_cost;
 
-                let (child_index, child_color) = if block_size1 < block_size2 {
-                    (0, restore_table[y1][x1][y2][xi].unwrap().2)
-                } else {
-                    (1, restore_table[y1][xi][y2][x2].unwrap().2)
+                best_choice = Choice {
+                    cmd: SimpleCommand::VerticalSplit(xi as u16),
+                    child_index: if block_size1 < block_size2 { 0 } else { 1 },
                 };
-                restore_table[y1][x1][y2][x2] = Some((SimpleCommand::VerticalSplit(xi), child_index, child_color));
             }
         }
 
-        // 点分割して再帰
-        for yi in y1 + 1..y2 {
-            for xi in x1 + 1..x2 {
-                inner(image, median_calculator, height, width, dp, restore_table, row_list, column_list, y1, x1, yi, xi);
-                inner(image, median_calculator, height, width, dp, restore_table, row_list, column_list, y1, xi, yi, x2);
-                inner(image, median_calculator, height, width, dp, restore_table, row_list, column_list, yi, xi, y2, x2);
-                inner(image, median_calculator, height, width, dp, restore_table, row_list, column_list, yi, x1, y2, xi);
-
-                // 色の塗り方を工夫することで、自分の色を塗ってから最大コストの色塗りを1つ回避できる
-                let block_size_list = vec![
-                    calculate_block_size(y1, x1, yi, xi),
-                    calculate_block_size(y1, xi, yi, x2),
-                    calculate_block_size(yi, xi, y2, x2),
-                    calculate_block_size(yi, x1, y2, xi),
-                ];
+        if expired {
+            return (best_cost, best_choice);
+        }
+
+        // 点分割. 一番内側のループなので、添字と大きさは行・列ごとに先に求めておく
+        let column_part_list = (x1 + 1..x2)
+            .filter(|xi| can_split_column(*xi, x2))
+            .map(|xi| (xi, RectIndexer::pair_index(x1, xi), RectIndexer::pair_index(xi, x2), column_list[xi] - column_list[x1], column_list[x2] - column_list[xi]))
+            .collect::<Vec<_>>();
+        for yi in (y1 + 1..y2).filter(|yi| can_split_row(*yi, y2)) {
+            let bottom = RectIndexer::pair_index(y1, yi) * indexer.column_pair_count;
+            let top = RectIndexer::pair_index(yi, y2) * indexer.column_pair_count;
+            let (bottom_height, top_height) = (row_list[yi] - row_list[y1], row_list[y2] - row_list[yi]);
+            for &(xi, left, right, left_width, right_width) in column_part_list.iter() {
+                let child_list = [bottom + left, bottom + right, top + right, top + left];
+
+                let block_size_list = [bottom_height * left_width, bottom_height * right_width, top_height * right_width, top_height * left_width];
                 let min_block_size = *block_size_list.iter().min().unwrap();
-                let max_color_cost = COLOR_COST * canvas_size as f64 / min_block_size as f64;
-                let self_color_cost = COLOR_COST * canvas_size as f64 / self_block_size as f64;
-
-                let point_cost = dp[y1][x1][yi][xi] + dp[y1][xi][yi][x2] + dp[yi][xi][y2][x2] + dp[yi][x1][y2][xi] + calculate_point_cut_cost(y1, x1, y2, x2) + self_color_cost - max_color_cost;
-
-                if dp[y1][x1][y2][x2] > point_cost {
-                    dp[y1][x1][y2][x2] = point_cost;
-
-                    let mut child_index = 0;
-                    let mut child_color = Color8::new(0, 0, 0, 0);
-                    let color_list = vec![
-                        restore_table[y1][x1][yi][xi].unwrap().2,
-                        restore_table[y1][xi][yi][x2].unwrap().2,
-                        restore_table[yi][xi][y2][x2].unwrap().2,
-                        restore_table[yi][x1][y2][xi].unwrap().2,
-                    ];
-                    for i in 0..4 {
-                        if block_size_list[0] == min_block_size {
-                            child_index = i;
-                            child_color = color_list[i];
-                            break;
-                        }
-                    }
-                    restore_table[y1][x1][y2][x2] = Some((SimpleCommand::PointSplit(yi, xi), child_index, child_color));
+                let max_color_cost = color_cost_of(min_block_size);
+
+                let point_cost = child_list.iter().map(|i| dp[*i]).sum::<f64>() + point_cut_cost + self_color_cost - max_color_cost;
+                if best_cost > point_cost {
+                    best_cost = point_cost;
+
+                    // 色塗りを省くのは一番小さい子
+                    let child_index = block_size_list.iter().position(|block_size| *block_size == min_block_size).unwrap();
+                    best_choice = Choice {
+                        cmd: SimpleCommand::PointSplit(yi as u16, xi as u16),
+                        child_index: child_index as u8,
+                    };
+                }
+            }
+        }
+        (best_cost, best_choice)
+    };
+
+    let thread_count = options.thread_count.max(1);
+    for height in 1..table_height {
+        for width in 1..table_width {
+            // 時間切れなら、重い色のコストの計算と点分割を諦めて線分割までで確定させる
+            let expired = deadline.is_expired();
+
+            let y1_count = table_height - height;
+            let chunk_size = y1_count.div_ceil(thread_count);
+            let result_list = std::thread::scope(|scope| {
+                let handle_list = (0..y1_count)
+                    .step_by(chunk_size)
+                    .map(|start| {
+                        let (dp, solve_rect) = (&dp, &solve_rect);
+                        scope.spawn(move || {
+                            let mut ret = vec![];
+                            for y1 in start..(start + chunk_size).min(y1_count) {
+                                for x1 in 0..table_width - width {
+                                    ret.push(solve_rect(dp, y1, x1, y1 + height, x1 + width, expired));
+                                }
+                            }
+                            ret
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                handle_list.into_iter().flat_map(|handle| handle.join().unwrap()).collect::<Vec<_>>()
+            });
+
+            // スレッドは y1 の昇順に、各スレッド内も y1, x1 の昇順に並んでいる
+            let mut result_iter = result_list.into_iter();
+            for y1 in 0..y1_count {
+                for x1 in 0..table_width - width {
+                    let (cost, choice) = result_iter.next().unwrap();
+                    let index = indexer.index(y1, x1, y1 + height, x1 + width);
+                    dp[index] = cost;
+                    choice_table[index] = choice;
                 }
             }
         }
     }
 
-    inner(
-        image,
-        &median_calculator,
-        image.height,
-        image.width,
-        &mut dp,
-        &mut restore_table,
-        row_list,
-        column_list,
-        0,
-        0,
-        row_list.len() - 1,
-        column_list.len() - 1,
-    );
+    // 分割した子のタイル. 順番は State の分割と同じ
+    let child_rect_list = |cmd: SimpleCommand, y1: usize, x1: usize, y2: usize, x2: usize| -> Vec<(usize, usize, usize, usize)> {
+        match cmd {
+            SimpleCommand::VerticalSplit(xi) => vec![(y1, x1, y2, xi as usize), (y1, xi as usize, y2, x2)],
+            SimpleCommand::HorizontalSplit(yi) => vec![(y1, x1, yi as usize, x2), (yi as usize, x1, y2, x2)],
+            SimpleCommand::PointSplit(yi, xi) => {
+                let (yi, xi) = (yi as usize, xi as usize);
+                vec![(y1, x1, yi, xi), (y1, xi, yi, x2), (yi, xi, y2, x2), (yi, x1, y2, xi)]
+            }
+            SimpleCommand::Color => vec![],
+        }
+    };
+
+    // 分割するタイルは child_index の子をたどった先のタイルの色で塗る
+    let color_of = |mut rect: (usize, usize, usize, usize)| -> Color8 {
+        loop {
+            let (y1, x1, y2, x2) = rect;
+            let Choice { cmd, child_index } = choice_table[indexer.index(y1, x1, y2, x2)];
+            if let SimpleCommand::Color = cmd {
+                return grid_color.color_cost(y1, x1, y2, x2).0;
+            }
+            rect = child_rect_list(cmd, y1, x1, y2, x2)[child_index as usize];
+        }
+    };
 
     // コマンドを復元
     let mut state = State::new(image.height, image.width);
@@ -227,52 +407,22 @@ pub fn solve_by_divisor(image: &Image, row_list: &Vec<usize>, column_list: &Vec<
     queue.push_back((0, 0, row_list.len() - 1, column_list.len() - 1, 0, true));
 
     while let Some((y1, x1, y2, x2, block_index, color_self)) = queue.pop_front() {
-        let (cmd, child_index, child_color) = restore_table[y1][x1][y2][x2].unwrap();
+        let Choice { cmd, child_index } = choice_table[indexer.index(y1, x1, y2, x2)];
+        if color_self {
+            state.apply(Command::Color(block_index, color_of((y1, x1, y2, x2))));
+        }
+        let child_block_index = state.block_list.len();
         match cmd {
-            SimpleCommand::VerticalSplit(xi) => {
-                let x = column_list[xi];
-                let child_block_index = state.block_list.len();
-                let cmd = Command::VerticalSplit(block_index, x);
-                if color_self {
-                    state.apply(Command::Color(block_index, child_color));
-                }
-                state.apply(cmd);
-                queue.push_back((y1, x1, y2, xi, child_block_index, child_index != 0));
-                queue.push_back((y1, xi, y2, x2, child_block_index + 1, child_index != 1));
-            }
-            SimpleCommand::HorizontalSplit(yi) => {
-                let y = row_list[yi];
-                let child_block_index = state.block_list.len();
-                let cmd = Command::HorizontalSplit(block_index, y);
-                if color_self {
-                    state.apply(Command::Color(block_index, child_color));
-                }
-                state.apply(cmd);
-                queue.push_back((y1, x1, yi, x2, child_block_index, child_index != 0));
-                queue.push_back((yi, x1, y2, x2, child_block_index + 1, child_index != 1));
-            }
-            SimpleCommand::PointSplit(yi, xi) => {
-                let y = row_list[yi];
-                let x = column_list[xi];
-                let cmd = Command::PointSplit(block_index, Pos::new(y, x));
-                let child_block_index = state.block_list.len();
-                if color_self {
-                    state.apply(Command::Color(block_index, child_color));
-                }
-                state.apply(cmd);
-                queue.push_back((y1, x1, yi, xi, child_block_index, child_index != 0));
-                queue.push_back((y1, xi, yi, x2, child_block_index + 1, child_index != 1));
-                queue.push_back((yi, xi, y2, x2, child_block_index + 2, child_index != 2));
-                queue.push_back((yi, x1, y2, xi, child_block_index + 3, child_index != 3));
-            }
-            SimpleCommand::Color(color) => {
-                if color_self {
-                    state.apply(Command::Color(block_index, color));
-                }
-            }
+            SimpleCommand::VerticalSplit(xi) => state.apply(Command::VerticalSplit(block_index, column_list[xi as usize])),
+            SimpleCommand::HorizontalSplit(yi) => state.apply(Command::HorizontalSplit(block_index, row_list[yi as usize])),
+            SimpleCommand::PointSplit(yi, xi) => state.apply(Command::PointSplit(block_index, Pos::new(row_list[yi as usize], column_list[xi as usize]))),
+            SimpleCommand::Color => {}
+        }
+        for (i, (y1, x1, y2, x2)) in child_rect_list(cmd, y1, x1, y2, x2).into_iter().enumerate() {
+            queue.push_back((y1, x1, y2, x2, child_block_index + i, child_index as usize != i));
         }
     }
-    state
+    (state, dp[indexer.index(0, 0, table_height - 1, table_width - 1)])
 }
 
 // 画素値で threashold 以上切れていそうな部分を見つけ、優先的に配置
@@ -293,7 +443,7 @@ pub fn detect_edge(image: &Image, threashold: f64) -> (Vec<usize>, Vec<usize>) {
             if !selected[i] && score_table[i] > threashold {
                 ret.push(i);
 
-                let si = if i <= RANGE_THREASHOLD { 0 } else { i - RANGE_THREASHOLD };
+                let si = i.saturating_sub(RANGE_THREASHOLD);
                 let ei = (i + RANGE_THREASHOLD).min(score_table.len());
                 for j in si..ei {
                     selected[j] = true;
@@ -334,12 +484,219 @@ pub fn detect_edge(image: &Image, threashold: f64) -> (Vec<usize>, Vec<usize>) {
     (select_value(&row_score_table), select_value(&col_score_table))
 }
 
+/// 今の格子の各セルを線で2つに分けたときの二乗誤差の減少量が最大になる線を、行・列合わせて1本ずつ貪欲に足す
+/// 行・列それぞれ budget 本 (端点を除く) まで. 返り値は detect_edge と同じく端点込み
+/// 画像の一部にしかない境界も、その部分の列で区切られた後なら拾える
+pub fn select_lines(image: &Image, budget: usize) -> (Vec<usize>, Vec<usize>) {
+    let rmse_sum = CumulativeRMSESum::new(image);
+    let sse = |sy: usize, sx: usize, ey: usize, ex: usize| -> f64 { rmse_sum.range_rmse(sy, sx, ey, ex).horizontal_add() };
+
+    // row_list で区切った帯ごとに、column_list のセルを y で切ったときの減少量
+    let best_cut = |row_list: &Vec<usize>, column_list: &Vec<usize>, transposed: bool| -> Option<(f64, usize)> {
+        let sse = |sy: usize, sx: usize, ey: usize, ex: usize| if transposed { sse(sx, sy, ex, ey) } else { sse(sy, sx, ey, ex) };
+        let mut best: Option<(f64, usize)> = None;
+        for band in row_list.windows(2) {
+            for y in band[0] + 1..band[1] {
+                let mut gain = 0.0;
+                for cell in column_list.windows(2) {
+                    gain += sse(band[0], cell[0], band[1], cell[1]) - sse(band[0], cell[0], y, cell[1]) - sse(y, cell[0], band[1], cell[1]);
+                }
+                if best.is_none_or(|v| v.0 < gain) {
+                    best = Some((gain, y));
+                }
+            }
+        }
+        best
+    };
+
+    let mut row_list = vec![0, image.height];
+    let mut column_list = vec![0, image.width];
+    loop {
+        let row_cut = if row_list.len() < budget + 2 { best_cut(&row_list, &column_list, false) } else { None };
+        let column_cut = if column_list.len() < budget + 2 { best_cut(&column_list, &row_list, true) } else { None };
+
+        let (list, (gain, v)) = match (row_cut, column_cut) {
+            (Some(r), Some(c)) if r.0 >= c.0 => (&mut row_list, r),
+            (Some(r), None) => (&mut row_list, r),
+            (_, Some(c)) => (&mut column_list, c),
+            (None, None) => break,
+        };
+        // 累積和の丸め誤差で、何も変わらない線を引かないように
+        if gain < 1.0 {
+            break;
+        }
+        let pos = list.partition_point(|u| *u < v);
+        list.insert(pos, v);
+    }
+    (row_list, column_list)
+}
+
 pub fn calculate_divisor_list(value: usize) -> Vec<usize> {
     let mut ret = vec![];
     for i in (2..value).rev() {
-        if value % i == 0 {
+        if value.is_multiple_of(i) {
             ret.push(i);
         }
     }
     ret
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{problem_loader::Problem, random::CachedRandom};
+    use std::{
+        path::Path,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn test_select_lines() {
+        // [100, 200) x [50, 300) だけ赤い画像
+        let mut image = Image {
+            height: 400,
+            width: 400,
+            buffer: vec![Color8::new(255, 255, 255, 255); 400 * 400],
+        };
+        for y in 100..200 {
+            for x in 50..300 {
+                image.buffer[y * 400 + x] = Color8::new(255, 0, 0, 255);
+            }
+        }
+        assert_eq!(select_lines(&image, 2), (vec![0, 100, 200, 400], vec![0, 50, 300, 400]));
+
+        // 線を引いても誤差が減らなければ引かない
+        let image = Image {
+            height: 400,
+            width: 400,
+            buffer: vec![Color8::new(255, 255, 255, 255); 400 * 400],
+        };
+        assert_eq!(select_lines(&image, 10), (vec![0, 400], vec![0, 400]));
+    }
+
+    #[test]
+    fn test_solve_by_divisor() {
+        // 25 x 25 ごとに色が違う画像は、その格子で切れば類似度コスト 0 で塗れる
+        let mut image = Image {
+            height: 100,
+            width: 100,
+            buffer: vec![Color8::default(); 100 * 100],
+        };
+        for y in 0..100 {
+            for x in 0..100 {
+                let (i, j) = (y / 25, x / 25);
+                image.buffer[y * 100 + x] = Color8::new((i * 60) as u8, (j * 60) as u8, ((i + j) * 30) as u8, 255);
+            }
+        }
+        let cost_model = CostModel::default();
+        let line_list = vec![0, 10, 25, 50, 75, 100];
+        let state = solve_by_divisor(&image, &cost_model, &line_list, &line_list, &Deadline::unlimited());
+        assert_eq!(similarity_cost(&image, &state), 0.0);
+
+        // 並列にしても、近似しても同じ解になる
+        for rect_cost in [RectCost::Exact, RectCost::Approximate] {
+            let options = DivisorOptions { rect_cost, thread_count: 3 };
+            let parallel_state = solve_by_divisor_with(&image, &cost_model, &line_list, &line_list, &Deadline::unlimited(), &options);
+            assert_eq!(parallel_state.get_command_list(), state.get_command_list());
+        }
+    }
+
+    #[test]
+    fn test_grid_color_table() {
+        // 返す類似度コストは、返す色で塗ったときの距離の和と一致する
+        let problem = Problem::load_from_dir(Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/../dataset")), 5);
+        let line_list = vec![0, 30, 100, 170, 200, 333, 400];
+        let table = GridColorTable::new(&problem.image, &line_list, &line_list);
+        for (y1, x1, y2, x2) in [(0, 0, 6, 6), (1, 2, 3, 5), (4, 0, 5, 1), (2, 3, 6, 4)] {
+            let (color, cost) = table.color_cost(y1, x1, y2, x2);
+            let rect = Rectangle::new(line_list[y1], line_list[x1], line_list[y2] - line_list[y1], line_list[x2] - line_list[x1]);
+            assert!((problem.image.rmse(&rect, &color) - cost).abs() < 1e-6 * cost.max(1.0));
+        }
+    }
+
+    #[test]
+    fn test_solve_by_divisor_cost() {
+        // 小さいランダム画像とランダムな格子で、復元した解のコストが DP のコストと丸め誤差の範囲で一致する
+        let mut rand = CachedRandom::new(100000, 0);
+        // 分割が安い問題 (26 以降) のコストだと点分割が選ばれやすい
+        let cheap_cost_model = CostModel {
+            line_cut: 2,
+            point_cut: 3,
+            ..CostModel::default()
+        };
+        for cost_model in [CostModel::default(), cheap_cost_model] {
+            for _ in 0..20 {
+                // 小さすぎると命令コストが勝って1色で塗るだけになる
+                let height = rand.next_int_range(32, 64) as usize;
+                let width = rand.next_int_range(32, 64) as usize;
+                let mut random_lines = |len: usize| {
+                    let mut line_list = (1..len).filter(|_| rand.next_int_range(0, 8) == 0).collect::<Vec<_>>();
+                    line_list.insert(0, 0);
+                    line_list.push(len);
+                    line_list
+                };
+                let row_list = random_lines(height);
+                let column_list = random_lines(width);
+
+                // 格子のタイルごとに色を決めて、たまに違う色の画素を混ぜる
+                let palette = (0..8)
+                    .map(|_| Color8::new(rand.next_int_range(0, 256) as u8, rand.next_int_range(0, 256) as u8, rand.next_int_range(0, 256) as u8, 255))
+                    .collect::<Vec<_>>();
+                let tile_color_list = (0..row_list.len() * column_list.len()).map(|_| palette[rand.next_int_range(0, 8) as usize]).collect::<Vec<_>>();
+                let mut buffer = vec![Color8::default(); height * width];
+                for y in 0..height {
+                    for x in 0..width {
+                        let (yi, xi) = (row_list.partition_point(|v| *v <= y), column_list.partition_point(|v| *v <= x));
+                        buffer[y * width + x] = if rand.next_int_range(0, 20) == 0 {
+                            palette[rand.next_int_range(0, 8) as usize]
+                        } else {
+                            tile_color_list[yi * column_list.len() + xi]
+                        };
+                    }
+                }
+                let image = Image { height, width, buffer };
+
+                let (state, dp_cost) = solve_by_divisor_with_cost(&image, &cost_model, &row_list, &column_list, &Deadline::unlimited(), &DivisorOptions::default());
+                let score = evaluate(&image, &state, &cost_model);
+                assert!((score - dp_cost).abs() <= 0.5 + 1e-6, "score {} != dp {} ({:?}, {:?})", score, dp_cost, row_list, column_list);
+            }
+        }
+    }
+
+    #[test]
+    fn test_solve_by_divisor_expired() {
+        // 時間切れの後は、格子が細かくてもすぐに (1色で塗るよりましな) 解を返す
+        let problem = Problem::load_from_dir(Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/../dataset")), 5);
+        let line_list = (0..=400).step_by(10).collect::<Vec<_>>();
+        let start = Instant::now();
+        let state = solve_by_divisor(&problem.image, &problem.cost_model, &line_list, &line_list, &Deadline::new(Some(Duration::ZERO)));
+        let elapsed = start.elapsed();
+        assert!(elapsed < Duration::from_secs(5), "{:?}", elapsed);
+
+        let mut single_color = State::new(400, 400);
+        single_color.apply(Command::Color(0, problem.image.mean_color(&Rectangle::new(0, 0, 400, 400))));
+        assert!(evaluate(&problem.image, &state, &problem.cost_model) < evaluate(&problem.image, &single_color, &problem.cost_model));
+    }
+
+    // cargo test --release -- --ignored bench_solve_by_divisor --nocapture
+    #[test]
+    #[ignore]
+    fn bench_solve_by_divisor() {
+        let problem = Problem::load_from_dir(Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/../dataset")), 5);
+        let thread_count = std::thread::available_parallelism().map_or(1, |v| v.get());
+        for (step, rect_cost) in [(20, RectCost::Exact), (20, RectCost::Approximate), (10, RectCost::Approximate), (5, RectCost::Approximate)] {
+            let line_list = (0..=400).step_by(step).collect::<Vec<_>>();
+            let mut thread_count_list = vec![1];
+            if thread_count > 1 {
+                thread_count_list.push(thread_count);
+            }
+            for thread_count in thread_count_list {
+                let options = DivisorOptions { rect_cost, thread_count };
+                let start = Instant::now();
+                let state = solve_by_divisor_with(&problem.image, &problem.cost_model, &line_list, &line_list, &Deadline::unlimited(), &options);
+                let score = evaluate(&problem.image, &state, &problem.cost_model);
+                eprintln!("{} lines, {:?}, {} threads: {:.1}s, score {}", line_list.len(), rect_cost, thread_count, start.elapsed().as_secs_f64(), score);
+            }
+        }
+    }
+}
@@ -1,6 +1,14 @@
+#![allow(clippy::needless_range_loop, clippy::too_many_arguments, clippy::type_complexity, clippy::ptr_arg)]
+
+mod batch;
 mod common_solver;
+#[allow(dead_code)]
 mod mincost_matching;
+mod portfolio;
+mod solver;
 mod solver1;
+mod solver10;
+mod solver11;
 mod solver2;
 mod solver3;
 mod solver4;
@@ -10,70 +18,481 @@ mod solver7;
 mod solver8;
 mod solver9;
 
-use clap::{App, Arg};
-use common::problem::{evaluate, Image, StateWithScore};
+use std::{path::Path, sync::Arc, time::Duration};
+
+use clap::{App, Arg, ArgMatches};
+use common::{
+    deadline::Deadline,
+    history::{self, RunInfo},
+    isl_parser::load_isl,
+    problem::{evaluate, State, StateWithScore},
+    problem_loader::Problem,
+    solution_store::SolutionStore,
+};
+use solver::{find_solver, solver_list, ProblemKind, SolverContext};
+use solver11::{AnnealingConfig, Schedule};
 
 fn main() {
     let app = App::new("xyzsolver")
         .version("1")
         .author("xyz600")
         .about("problem solver for icfpc 2022")
-        .arg(Arg::with_name("problem-id").help("input problem id").short('i').long("problem-id").required(true).takes_value(true))
         .arg(
-            Arg::with_name("solver-type")
-                .help("select solver type to use. set 1 ~ 8 \n  (solver 3 | 4 | 5 is only available when use-twin-image is on.)\n  solver 7 is for only problem 1")
+            Arg::with_name("problem-id")
+                .help("input problem id")
+                .short('i')
+                .long("problem-id")
+                .required_unless_present("list-solvers")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("solver")
+                .help("solver name (or its number) to use. see --list-solvers")
                 .short('s')
-                .long("solver-type")
-                .required(true)
+                .long("solver")
+                .alias("solver-type")
+                .required_unless_present("list-solvers")
                 .takes_value(true),
         )
         .arg(
             Arg::with_name("use-twin-image")
-                .help("use twin image for problem 26..35")
+                .help("start from dataset/{id}.initial.json (problem 26..40)")
                 .short('t')
                 .long("use-twin-image")
                 .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::with_name("time-limit")
+                .help("time budget in seconds. the solver returns its best state so far when it expires")
+                .long("time-limit")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("archive-final")
+                .help("keep the final state in solution/history even if it does not improve the best")
+                .long("archive-final")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::with_name("list-solvers")
+                .help("print available solvers and exit")
+                .long("list-solvers")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .subcommand_negates_reqs(true)
+        .subcommand(
+            App::new("portfolio")
+                .about("run several solvers on one problem and keep the best")
+                .arg(Arg::with_name("problem-id").help("input problem id").short('i').long("problem-id").required(true).takes_value(true))
+                .arg(
+                    Arg::with_name("solvers")
+                        .help("comma separated solver names. default: all solvers applicable to the problem")
+                        .short('s')
+                        .long("solvers")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("time-limit")
+                        .help("time budget in seconds for the whole portfolio")
+                        .long("time-limit")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("parallel")
+                        .help("run each solver on its own thread")
+                        .short('p')
+                        .long("parallel")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::with_name("archive-final")
+                        .help("keep the final state of every solver in solution/history")
+                        .long("archive-final")
+                        .action(clap::ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            App::new("batch")
+                .about("run portfolio on many problems with a thread pool")
+                .arg(
+                    Arg::with_name("problems")
+                        .help("problem id ranges. e.g. 1-25,36-40")
+                        .short('i')
+                        .long("problems")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("solvers")
+                        .help("comma separated solver names. solvers not applicable to a problem are skipped. default: all")
+                        .short('s')
+                        .long("solvers")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("threads")
+                        .help("number of problems solved at the same time. default: number of cpus")
+                        .short('j')
+                        .long("threads")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("time-limit")
+                        .help("time budget in seconds for each problem")
+                        .long("time-limit")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("parallel")
+                        .help("also run the solvers of each problem on their own threads")
+                        .short('p')
+                        .long("parallel")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::with_name("archive-final")
+                        .help("keep the final state of every solver in solution/history")
+                        .long("archive-final")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::with_name("report")
+                        .help("json report path")
+                        .long("report")
+                        .default_value("solution/batch/report.json")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            App::new("verify")
+                .about("check that the stored ISL, PNG and score of each problem agree")
+                .arg(
+                    Arg::with_name("problems")
+                        .help("problem id ranges. e.g. 1-25,36-40")
+                        .short('i')
+                        .long("problems")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            App::new("polish")
+                .about("hill climb the cut positions of a solution and save it if it is the best")
+                .arg(Arg::with_name("problem-id").help("input problem id").short('i').long("problem-id").required(true).takes_value(true))
+                .arg(
+                    Arg::with_name("isl")
+                        .help("ISL file to polish. default: the stored best solution")
+                        .long("isl")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("blank")
+                        .help("the ISL starts from a blank canvas. default: dataset/{id}.initial.json if exists")
+                        .long("blank")
+                        .requires("isl")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(Arg::with_name("time-limit").help("time budget in seconds").long("time-limit").takes_value(true)),
+        )
+        .subcommand(
+            App::new("anneal")
+                .about("simulated annealing on the cut tree of a solution and save it if it is the best")
+                .arg(Arg::with_name("problem-id").help("input problem id").short('i').long("problem-id").required(true).takes_value(true))
+                .arg(
+                    Arg::with_name("isl")
+                        .help("ISL file to start from. default: the stored best solution")
+                        .long("isl")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("blank")
+                        .help("the ISL starts from a blank canvas. default: dataset/{id}.initial.json if exists")
+                        .long("blank")
+                        .requires("isl")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(Arg::with_name("time-limit").help("time budget in seconds").long("time-limit").takes_value(true))
+                .arg(Arg::with_name("start-temp").help("initial temperature").long("start-temp").takes_value(true))
+                .arg(Arg::with_name("end-temp").help("final temperature").long("end-temp").takes_value(true))
+                .arg(
+                    Arg::with_name("schedule")
+                        .help("how the temperature decreases")
+                        .long("schedule")
+                        .takes_value(true)
+                        .possible_values(["linear", "exponential"]),
+                )
+                .arg(
+                    Arg::with_name("iteration")
+                        .help("number of iterations when there is no time limit")
+                        .long("iteration")
+                        .takes_value(true),
+                )
+                .arg(Arg::with_name("seed").help("random seed").long("seed").takes_value(true)),
+        )
+        .subcommand(
+            App::new("history")
+                .about("list archived solutions of a problem, or restore one of them as the best")
+                .arg(Arg::with_name("problem-id").help("input problem id").short('i').long("problem-id").required(true).takes_value(true))
+                .arg(
+                    Arg::with_name("restore")
+                        .help("history index to write back to solution/ regardless of its score")
+                        .long("restore")
+                        .takes_value(true),
+                ),
         );
 
     let matches = app.get_matches();
 
+    if let Some(sub_matches) = matches.subcommand_matches("portfolio") {
+        run_portfolio(sub_matches);
+        return;
+    }
+    if let Some(sub_matches) = matches.subcommand_matches("batch") {
+        run_batch(sub_matches);
+        return;
+    }
+    if let Some(sub_matches) = matches.subcommand_matches("verify") {
+        run_verify(sub_matches);
+        return;
+    }
+    if let Some(sub_matches) = matches.subcommand_matches("polish") {
+        run_polish(sub_matches);
+        return;
+    }
+    if let Some(sub_matches) = matches.subcommand_matches("anneal") {
+        run_anneal(sub_matches);
+        return;
+    }
+    if let Some(sub_matches) = matches.subcommand_matches("history") {
+        run_history(sub_matches);
+        return;
+    }
+
+    if matches.get_flag("list-solvers") {
+        for (i, solver) in solver_list().iter().enumerate() {
+            let kind_list = solver.supported_kinds().iter().map(|kind| kind.name()).collect::<Vec<_>>();
+            println!("{:>2} {:<18} [{}] {}", i + 1, solver.name(), kind_list.join(", "), solver.description());
+        }
+        return;
+    }
+
+    let problem_id = matches.value_of("problem-id").unwrap().parse::<usize>().unwrap();
+    let problem = Problem::load(problem_id);
+
+    let kind = if matches.get_flag("use-twin-image") { ProblemKind::Twin } else { ProblemKind::Blank };
+    let context = SolverContext {
+        kind,
+        deadline: Deadline::new(parse_time_limit(&matches)),
+        archive_final: matches.get_flag("archive-final"),
+    };
+
+    let solver_name = matches.value_of("solver").unwrap();
+    let Some(solver) = find_solver(solver_name) else {
+        eprintln!("unknown solver '{}'. see --list-solvers", solver_name);
+        std::process::exit(1);
+    };
+    if let Err(message) = solver.check(&problem, &context) {
+        eprintln!("{}", message);
+        std::process::exit(1);
+    }
+
+    let entry = portfolio::run_solver(&problem, solver.as_ref(), &context);
+    StateWithScore {
+        score: entry.score,
+        state: entry.state,
+    }
+    .save_if_global_best_as(problem_id, &entry.run_info);
+}
+
+fn parse_time_limit(matches: &ArgMatches) -> Option<Duration> {
+    matches.value_of("time-limit").map(|v| Duration::from_secs_f64(v.parse().unwrap()))
+}
+
+fn run_portfolio(matches: &ArgMatches) {
+    let problem_id = matches.value_of("problem-id").unwrap().parse::<usize>().unwrap();
+    let problem = Problem::load(problem_id);
+    let context = SolverContext {
+        kind: ProblemKind::of(&problem),
+        deadline: Deadline::new(parse_time_limit(matches)),
+        archive_final: matches.get_flag("archive-final"),
+    };
+
+    let solver_list = match portfolio::select_solvers(&problem, &context, matches.value_of("solvers"), true) {
+        Ok(solver_list) => solver_list,
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+    };
+
+    let result = if matches.get_flag("parallel") {
+        portfolio::run_parallel(Arc::new(problem), solver_list, &context)
+    } else {
+        portfolio::run_sequential(&problem, solver_list, &context)
+    };
+    result.print_summary();
+    result.save_report(problem_id);
+
+    if let Some(best) = result.best() {
+        println!("winner: {}", best.solver_name);
+        StateWithScore {
+            score: best.score,
+            state: best.state.clone(),
+        }
+        .save_if_global_best_as(problem_id, &best.run_info);
+    }
+}
+
+fn run_batch(matches: &ArgMatches) {
+    let problem_id_list = match batch::parse_problem_id_list(matches.value_of("problems").unwrap()) {
+        Ok(problem_id_list) => problem_id_list,
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+    };
+    let solver_names = matches.value_of("solvers").map(|v| v.to_string());
+    if let Some(solver_names) = &solver_names {
+        for solver_name in solver_names.split(',') {
+            if find_solver(solver_name.trim()).is_none() {
+                eprintln!("unknown solver '{}'. see --list-solvers", solver_name);
+                std::process::exit(1);
+            }
+        }
+    }
+    let thread_count = matches
+        .value_of("threads")
+        .map_or_else(|| std::thread::available_parallelism().map_or(1, |v| v.get()), |v| v.parse().unwrap());
+
+    let entry_list = batch::run_batch(batch::BatchConfig {
+        problem_id_list,
+        solver_names,
+        thread_count,
+        time_limit: parse_time_limit(matches),
+        parallel_solvers: matches.get_flag("parallel"),
+        archive_final: matches.get_flag("archive-final"),
+    });
+    batch::print_summary(&entry_list);
+    batch::save_report(&entry_list, Path::new(matches.value_of("report").unwrap()));
+}
+
+fn run_verify(matches: &ArgMatches) {
+    let problem_id_list = match batch::parse_problem_id_list(matches.value_of("problems").unwrap()) {
+        Ok(problem_id_list) => problem_id_list,
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+    };
+    let store = SolutionStore::default();
+    let mut success = true;
+    for problem_id in problem_id_list.into_iter() {
+        match store.verify(&Problem::load(problem_id)) {
+            Ok(()) => println!("problem {}: ok", problem_id),
+            Err(message) => {
+                println!("{}", message);
+                success = false;
+            }
+        }
+    }
+    if !success {
+        std::process::exit(1);
+    }
+}
+
+fn run_history(matches: &ArgMatches) {
     let problem_id = matches.value_of("problem-id").unwrap().parse::<usize>().unwrap();
-    let input_filepath = format!("dataset/{}.png", problem_id);
-    let image = Image::new(input_filepath.as_str());
-
-    let use_twin_image = matches.get_flag("use-twin-image");
-
-    let solver_type = matches.value_of("solver-type").unwrap();
-
-    let final_state = if use_twin_image {
-        if solver_type == "3" {
-            solver3::solve(problem_id, &image)
-        } else if solver_type == "4" {
-            solver4::solve(problem_id, &image)
-        } else if solver_type == "5" {
-            solver5::solve(problem_id, &image)
-        } else if solver_type == "9" {
-            solver9::solve(problem_id, &image)
-        } else {
-            panic!("unknown solver");
+    let store = SolutionStore::default();
+
+    if let Some(index) = matches.value_of("restore") {
+        if let Err(message) = store.restore(problem_id, index.parse().unwrap()) {
+            eprintln!("{}", message);
+            std::process::exit(1);
         }
+        println!("restored history entry {} of problem {}", index, problem_id);
+        return;
+    }
+
+    println!("{:>5} {:>10} {:<18} {:>10} {:>8} {:<16} params", "index", "timestamp", "solver", "score", "improved", "revision");
+    for entry in store.history(problem_id).iter() {
+        println!(
+            "{:>5} {:>10} {:<18} {:>10} {:>8} {:<16} {}",
+            entry.index,
+            entry.timestamp,
+            entry.solver,
+            entry.score,
+            if entry.improved { "*" } else { "" },
+            entry.git_revision.as_deref().unwrap_or("-"),
+            entry.params
+        );
+    }
+}
+
+/// polish / anneal の開始状態. --isl があればそれを、なければ保存済みの最良解を読む
+/// --isl は問題の初期状態 (--blank なら白紙の canvas) から再生する. 読めなければ終了する
+fn load_start_state(problem: &Problem, matches: &ArgMatches) -> Option<State> {
+    let Some(isl_filepath) = matches.value_of("isl") else {
+        return StateWithScore::load(problem.id).map(|result| result.state);
+    };
+    let init_state = if matches.get_flag("blank") {
+        State::new(problem.image.height, problem.image.width)
     } else {
-        if solver_type == "1" {
-            solver1::solve(problem_id, &image)
-        } else if solver_type == "2" {
-            solver2::solve(problem_id, &image)
-        } else if solver_type == "6" {
-            solver6::solve(problem_id, &image)
-        } else if solver_type == "7" {
-            assert_eq!(problem_id, 1);
-            solver7::solve(problem_id, &image)
-        } else if solver_type == "8" {
-            solver8::solve(problem_id, &image)
-        } else {
-            panic!("unknown solver");
+        problem.initial_state()
+    };
+    match load_isl(Path::new(isl_filepath), init_state) {
+        Ok(state) => Some(state),
+        Err(err) => {
+            eprintln!("{}: {}", isl_filepath, err);
+            std::process::exit(1);
         }
+    }
+}
+
+fn run_polish(matches: &ArgMatches) {
+    let problem_id = matches.value_of("problem-id").unwrap().parse::<usize>().unwrap();
+    let problem = Problem::load(problem_id);
+
+    let Some(state) = load_start_state(&problem, matches) else {
+        eprintln!("problem {} has no stored solution", problem_id);
+        std::process::exit(1);
+    };
+
+    history::set_current_run(RunInfo {
+        solver: "polish".to_string(),
+        params: format!("isl={}", matches.value_of("isl").unwrap_or("stored")),
+    });
+    let before = evaluate(&problem.image, &state, &problem.cost_model);
+    let state = solver10::polish(&problem, state, &Deadline::new(parse_time_limit(matches)));
+    let state = portfolio::post_process(&problem, state, "polish");
+    let score = evaluate(&problem.image, &state, &problem.cost_model);
+    println!("{} -> {}", before, score);
+    StateWithScore { score, state }.save_if_global_best(problem_id);
+}
+
+fn run_anneal(matches: &ArgMatches) {
+    let problem_id = matches.value_of("problem-id").unwrap().parse::<usize>().unwrap();
+    let problem = Problem::load(problem_id);
+
+    let state = load_start_state(&problem, matches).unwrap_or_else(|| problem.initial_state());
+
+    let default_config = AnnealingConfig::default();
+    let config = AnnealingConfig {
+        start_temperature: matches.value_of("start-temp").map_or(default_config.start_temperature, |v| v.parse().unwrap()),
+        end_temperature: matches.value_of("end-temp").map_or(default_config.end_temperature, |v| v.parse().unwrap()),
+        schedule: matches.value_of("schedule").map_or(default_config.schedule, |v| Schedule::parse(v).unwrap()),
+        max_iteration: matches.value_of("iteration").map_or(default_config.max_iteration, |v| v.parse().unwrap()),
+        seed: matches.value_of("seed").map_or(default_config.seed, |v| v.parse().unwrap()),
+        ..default_config
     };
 
-    let score = evaluate(&image, &final_state);
-    StateWithScore { score, state: final_state }.save_if_global_best(problem_id);
+    history::set_current_run(RunInfo {
+        solver: "anneal".to_string(),
+        params: format!("isl={} {}", matches.value_of("isl").unwrap_or("stored"), config.params()),
+    });
+    let before = evaluate(&problem.image, &state, &problem.cost_model);
+    let state = solver11::anneal(&problem, &state, &config, &Deadline::new(parse_time_limit(matches)));
+    let state = portfolio::post_process(&problem, state, "anneal");
+    let score = evaluate(&problem.image, &state, &problem.cost_model);
+    println!("{} -> {}", before, score);
+    StateWithScore { score, state }.save_if_global_best(problem_id);
 }
@@ -40,9 +40,9 @@ impl MinCostFlow {
     }
     fn add_edge(&mut self, from: usize, to: usize, cap: Cap, cost: Cost) {
         let fst = Edge {
-            to: to,
-            cap: cap,
-            cost: cost,
+            to,
+            cap,
+            cost,
             rev: self.graph[to].len(),
         };
         self.graph[from].push(fst);
@@ -58,7 +58,7 @@ impl MinCostFlow {
     // whose source is s, sink is t, and flow is f.
     fn min_cost_flow(&mut self, s: usize, t: usize, mut f: Cap) -> Cost {
         let n = self.n;
-        let inf: Cost = std::i64::MAX / 10; // ?????
+        let inf: Cost = i64::MAX / 10; // ?????
         let mut res = 0;
         let h = &mut self.h;
         let dist = &mut self.dist;
@@ -107,7 +107,7 @@ impl MinCostFlow {
                 i = pv;
             }
         }
-        return res;
+        res
     }
 }
 
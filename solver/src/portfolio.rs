@@ -0,0 +1,324 @@
+use std::{
+    fs::File,
+    io::BufWriter,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::{
+    history::{self, RunInfo},
+    optimizer::{optimize_commands, refine_colors},
+    problem::{evaluate, State, StateWithScore},
+    problem_loader::Problem,
+};
+
+use crate::solver::{find_solver, solver_list, Solver, SolverContext};
+
+/// 1つの solver の実行結果
+pub struct PortfolioEntry {
+    pub solver_name: String,
+    // 保存するときに history に残す solver の情報
+    pub run_info: RunInfo,
+    pub score: f64,
+    pub elapsed: Duration,
+    pub state: State,
+}
+
+#[derive(serde::Serialize)]
+struct EntryReport {
+    solver: String,
+    score: f64,
+    elapsed_sec: f64,
+}
+
+#[derive(serde::Serialize)]
+struct PortfolioReport {
+    problem_id: usize,
+    winner: Option<String>,
+    // 時間切れで結果の出なかった solver
+    timed_out: Vec<String>,
+    // panic した solver
+    failed: Vec<String>,
+    results: Vec<EntryReport>,
+}
+
+pub struct PortfolioResult {
+    // score の昇順
+    pub entry_list: Vec<PortfolioEntry>,
+    pub timed_out: Vec<String>,
+    pub failed: Vec<String>,
+}
+
+impl PortfolioResult {
+    pub fn best(&self) -> Option<&PortfolioEntry> {
+        self.entry_list.first()
+    }
+
+    pub fn print_summary(&self) {
+        for entry in self.entry_list.iter() {
+            println!("{:<18} {:>10} {:>8.1}s", entry.solver_name, entry.score, entry.elapsed.as_secs_f64());
+        }
+        for solver_name in self.timed_out.iter() {
+            println!("{:<18} {:>10}", solver_name, "timeout");
+        }
+        for solver_name in self.failed.iter() {
+            println!("{:<18} {:>10}", solver_name, "failed");
+        }
+    }
+
+    /// どの solver が勝ったかを solution/portfolio/{id}.json に残す
+    pub fn save_report(&self, problem_id: usize) {
+        let report = PortfolioReport {
+            problem_id,
+            winner: self.best().map(|entry| entry.solver_name.clone()),
+            timed_out: self.timed_out.clone(),
+            failed: self.failed.clone(),
+            results: self
+                .entry_list
+                .iter()
+                .map(|entry| EntryReport {
+                    solver: entry.solver_name.clone(),
+                    score: entry.score,
+                    elapsed_sec: entry.elapsed.as_secs_f64(),
+                })
+                .collect(),
+        };
+        std::fs::create_dir_all("solution/portfolio").unwrap();
+        let path_str = format!("solution/portfolio/{}.json", problem_id);
+        let writer = BufWriter::new(File::create(Path::new(&path_str)).unwrap());
+        serde_json::to_writer_pretty(writer, &report).unwrap();
+    }
+}
+
+/// solver_names (カンマ区切り) の solver を返す. None なら problem に使えるもの全部
+/// strict なら problem に使えない solver が指定されているとエラー、そうでなければ除く
+pub fn select_solvers(problem: &Problem, context: &SolverContext, solver_names: Option<&str>, strict: bool) -> Result<Vec<Box<dyn Solver>>, String> {
+    let Some(solver_names) = solver_names else {
+        return Ok(solver_list().into_iter().filter(|solver| solver.check(problem, context).is_ok()).collect());
+    };
+    let mut ret = vec![];
+    for solver_name in solver_names.split(',') {
+        let Some(solver) = find_solver(solver_name.trim()) else {
+            return Err(format!("unknown solver '{}'. see --list-solvers", solver_name));
+        };
+        match solver.check(problem, context) {
+            Ok(()) => ret.push(solver),
+            Err(message) if strict => return Err(message),
+            Err(_) => {}
+        }
+    }
+    Ok(ret)
+}
+
+/// solver の出力の無駄な命令を消して色を調整する
+pub fn post_process(problem: &Problem, state: State, solver_name: &str) -> State {
+    let (state, saved_cost) = optimize_commands(&problem.image, &state, &problem.cost_model);
+    if saved_cost > 0 {
+        eprintln!("{}: optimizer saved {}", solver_name, saved_cost);
+    }
+    let (state, gain) = refine_colors(&problem.image, &state, &problem.cost_model);
+    if gain > 0.0 {
+        eprintln!("{}: color refinement saved {}", solver_name, gain);
+    }
+    state
+}
+
+/// solver を1つ実行し、最後に post_process をかける
+/// 実行中に保存される解は、この solver が作ったものとして history に残る
+pub fn run_solver(problem: &Problem, solver: &dyn Solver, context: &SolverContext) -> PortfolioEntry {
+    let run_info = RunInfo {
+        solver: solver.name().to_string(),
+        params: context.params(),
+    };
+    history::set_current_run(run_info.clone());
+    let start = Instant::now();
+    // Blank の solver は白紙から解を作るので、初期画像のある問題ではその上に再生し直す
+    let state = problem.replay_on_initial_state(&solver.solve(problem, context));
+    let state = post_process(problem, state, solver.name());
+    let elapsed = Instant::now() - start;
+    let score = evaluate(&problem.image, &state, &problem.cost_model);
+    let result = StateWithScore { score, state };
+    if context.archive_final {
+        result.save_final_as(problem.id, &run_info);
+    }
+    PortfolioEntry {
+        solver_name: solver.name().to_string(),
+        run_info,
+        score,
+        elapsed,
+        state: result.state,
+    }
+}
+
+/// run_solver と同じだが、solver が panic したらメッセージを出して Err を返す
+fn run_solver_catching_panic(problem: &Problem, solver: &dyn Solver, context: &SolverContext) -> Result<PortfolioEntry, String> {
+    panic::catch_unwind(AssertUnwindSafe(|| run_solver(problem, solver, context))).map_err(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|v| v.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        eprintln!("{}: solver failed: {}", solver.name(), message);
+        message
+    })
+}
+
+/// solver_list を順に実行する
+/// 各 solver には残り時間を残りの solver 数で等分した時間を割り当てる
+/// 時間切れ後の solver は実行しない. panic した solver の結果は捨てる
+pub fn run_sequential(problem: &Problem, solver_list: Vec<Box<dyn Solver>>, context: &SolverContext) -> PortfolioResult {
+    let mut entry_list = vec![];
+    let mut timed_out = vec![];
+    let mut failed = vec![];
+    let solver_count = solver_list.len();
+    for (i, solver) in solver_list.into_iter().enumerate() {
+        if context.deadline.is_expired() {
+            timed_out.push(solver.name().to_string());
+            continue;
+        }
+        let mut solver_context = context.clone();
+        if let Some(remaining) = context.deadline.remaining() {
+            solver_context.deadline = context.deadline.child(remaining / (solver_count - i) as u32);
+        }
+        match run_solver_catching_panic(problem, solver.as_ref(), &solver_context) {
+            Ok(entry) => entry_list.push(entry),
+            Err(_) => failed.push(solver.name().to_string()),
+        }
+    }
+    entry_list.sort_by(|e1, e2| e1.score.partial_cmp(&e2.score).unwrap());
+    PortfolioResult { entry_list, timed_out, failed }
+}
+
+/// 時間切れの後、solver が最良解を返すまで待つ時間
+const GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// solver ごとにスレッドを立てて同時に実行する
+/// solver は全員同じ deadline で打ち切る
+/// deadline から GRACE_PERIOD 経っても終わらなかった solver と、panic した solver の結果は捨てる (スレッドはプロセス終了まで残る)
+pub fn run_parallel(problem: Arc<Problem>, solver_list: Vec<Box<dyn Solver>>, context: &SolverContext) -> PortfolioResult {
+    let start = Instant::now();
+    let wait_limit = context.deadline.remaining().map(|remaining| remaining + GRACE_PERIOD);
+    let mut remaining = solver_list.iter().map(|solver| solver.name().to_string()).collect::<Vec<_>>();
+
+    let (sender, receiver) = mpsc::channel();
+    for solver in solver_list.into_iter() {
+        let problem = problem.clone();
+        let context = context.clone();
+        let sender = sender.clone();
+        thread::spawn(move || {
+            let result = run_solver_catching_panic(&problem, solver.as_ref(), &context);
+            // 受け取り側が時間切れで終わっていれば送れないが、それで良い
+            let _ = sender.send((solver.name().to_string(), result));
+        });
+    }
+    drop(sender);
+
+    let mut entry_list = vec![];
+    let mut failed = vec![];
+    while !remaining.is_empty() {
+        let received = if let Some(limit) = wait_limit {
+            let elapsed = Instant::now() - start;
+            if elapsed >= limit {
+                break;
+            }
+            match receiver.recv_timeout(limit - elapsed) {
+                Ok(received) => Some(received),
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => None,
+            }
+        } else {
+            receiver.recv().ok()
+        };
+        // 送らずに終わったスレッドがあれば、残りは失敗として扱う
+        let Some((solver_name, result)) = received else {
+            failed.append(&mut remaining);
+            break;
+        };
+        remaining.retain(|name| *name != solver_name);
+        match result {
+            Ok(entry) => entry_list.push(entry),
+            Err(_) => failed.push(solver_name),
+        }
+    }
+    // 残っている solver には止まってもらう
+    context.deadline.cancel();
+    entry_list.sort_by(|e1, e2| e1.score.partial_cmp(&e2.score).unwrap());
+    PortfolioResult {
+        entry_list,
+        timed_out: remaining,
+        failed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::solver::ProblemKind;
+    use common::deadline::Deadline;
+
+    struct BlankSolver;
+
+    impl Solver for BlankSolver {
+        fn name(&self) -> &'static str {
+            "blank"
+        }
+
+        fn description(&self) -> &'static str {
+            "returns the initial state"
+        }
+
+        fn supported_kinds(&self) -> &'static [ProblemKind] {
+            &[ProblemKind::Blank]
+        }
+
+        fn solve(&self, problem: &Problem, _context: &SolverContext) -> State {
+            problem.initial_state()
+        }
+    }
+
+    struct PanicSolver;
+
+    impl Solver for PanicSolver {
+        fn name(&self) -> &'static str {
+            "panic"
+        }
+
+        fn description(&self) -> &'static str {
+            "always panics"
+        }
+
+        fn supported_kinds(&self) -> &'static [ProblemKind] {
+            &[ProblemKind::Blank]
+        }
+
+        fn solve(&self, _problem: &Problem, _context: &SolverContext) -> State {
+            panic!("broken solver");
+        }
+    }
+
+    #[test]
+    fn test_solver_panic() {
+        // panic した solver があっても、他の solver の結果は残る
+        let load_problem = || Problem::load_from_dir(Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/../dataset")), 1);
+        let problem = load_problem();
+        for time_limit in [None, Some(Duration::from_secs(60))] {
+            let context = SolverContext {
+                kind: ProblemKind::Blank,
+                deadline: Deadline::new(time_limit),
+                archive_final: false,
+            };
+            let solver_list = || -> Vec<Box<dyn Solver>> { vec![Box::new(PanicSolver), Box::new(BlankSolver)] };
+            for result in [run_sequential(&problem, solver_list(), &context), run_parallel(Arc::new(load_problem()), solver_list(), &context)] {
+                assert_eq!(result.entry_list.iter().map(|entry| entry.solver_name.as_str()).collect::<Vec<_>>(), vec!["blank"]);
+                assert_eq!(result.failed, vec!["panic".to_string()]);
+                assert!(result.timed_out.is_empty());
+            }
+        }
+    }
+}
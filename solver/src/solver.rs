@@ -0,0 +1,118 @@
+use common::{deadline::Deadline, problem::State, problem_loader::Problem};
+
+use crate::{solver1, solver10, solver11, solver2, solver3, solver4, solver5, solver6, solver7, solver8, solver9};
+
+/// どの初期状態から解くか
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ProblemKind {
+    // 白紙の canvas 1 ブロックから始める
+    Blank,
+    // dataset/{id}.initial.json のブロック群から始める (26..40)
+    Twin,
+}
+
+impl ProblemKind {
+    /// 初期ブロックが複数あれば Twin
+    /// 36..40 の初期ブロックは canvas 全体の 1 つだけなので Blank として扱う
+    /// Blank の solver が白紙から作った解は、Problem::replay_on_initial_state で初期画像の上に再生し直す
+    pub fn of(problem: &Problem) -> ProblemKind {
+        match &problem.config {
+            Some(config) if config.blocks.len() > 1 => ProblemKind::Twin,
+            _ => ProblemKind::Blank,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ProblemKind::Blank => "blank",
+            ProblemKind::Twin => "twin",
+        }
+    }
+}
+
+/// solve に渡す実行時の設定
+#[derive(Clone)]
+pub struct SolverContext {
+    pub kind: ProblemKind,
+    // 時間切れになったら solver はそれまでの最良解を返す
+    pub deadline: Deadline,
+    // 最良解を更新しなくても、solver の最終結果を history に残す
+    pub archive_final: bool,
+}
+
+impl SolverContext {
+    /// history に残す実行時の設定
+    pub fn params(&self) -> String {
+        let time_limit = self.deadline.remaining().map_or("none".to_string(), |v| format!("{:.0}s", v.as_secs_f64()));
+        format!("kind={} time_limit={}", self.kind.name(), time_limit)
+    }
+}
+
+pub trait Solver: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn description(&self) -> &'static str;
+
+    fn supported_kinds(&self) -> &'static [ProblemKind];
+
+    /// 解けない組み合わせは solve の前に理由を返す
+    fn check(&self, problem: &Problem, context: &SolverContext) -> Result<(), String> {
+        if problem.config.is_none() && context.kind == ProblemKind::Twin {
+            return Err(format!("problem {} has no initial config", problem.id));
+        }
+        if !self.supported_kinds().contains(&context.kind) {
+            return Err(format!("solver '{}' does not support {} problems", self.name(), context.kind.name()));
+        }
+        Ok(())
+    }
+
+    fn solve(&self, problem: &Problem, context: &SolverContext) -> State;
+}
+
+/// 登録されている solver 一覧
+/// 旧来の番号 (-s 1 など) は index + 1 で引ける
+pub fn solver_list() -> Vec<Box<dyn Solver>> {
+    vec![
+        Box::new(solver1::BeamSolver),
+        Box::new(solver2::DivisorSolver),
+        Box::new(solver3::TwinMedianSolver),
+        Box::new(solver4::TwinMergeLineSolver),
+        Box::new(solver5::TwinMergeAllSolver),
+        Box::new(solver6::DivisorClimbSolver),
+        Box::new(solver7::Problem1Solver),
+        Box::new(solver8::KMeansSolver),
+        Box::new(solver9::TwinMergeClimbSolver),
+        Box::new(solver10::CutClimbSolver),
+        Box::new(solver11::AnnealingSolver),
+    ]
+}
+
+pub fn find_solver(name: &str) -> Option<Box<dyn Solver>> {
+    let mut list = solver_list();
+    if let Ok(number) = name.parse::<usize>() {
+        if 1 <= number && number <= list.len() {
+            return Some(list.swap_remove(number - 1));
+        }
+    }
+    list.into_iter().find(|solver| solver.name() == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_solver() {
+        assert_eq!(find_solver("6").unwrap().name(), "divisor-climb");
+        assert_eq!(find_solver("beam").unwrap().name(), "beam");
+        assert_eq!(find_solver("10").unwrap().name(), "cut-climb");
+        assert_eq!(find_solver("11").unwrap().name(), "anneal");
+        assert!(find_solver("12").is_none());
+        assert!(find_solver("unknown").is_none());
+
+        let name_list = solver_list().iter().map(|solver| solver.name()).collect::<Vec<_>>();
+        for (i, name) in name_list.iter().enumerate() {
+            assert!(!name_list[i + 1..].contains(name));
+        }
+    }
+}
@@ -1,5 +1,8 @@
 use common::cumulative_sum::*;
-use common::problem::*;
+use common::evaluator::IncrementalEvaluator;
+use common::{deadline::Deadline, problem::*, problem_loader::Problem};
+
+use crate::solver::{ProblemKind, Solver, SolverContext};
 
 fn detect_edge(image: &Image, threashold: f64) -> (Vec<usize>, Vec<usize>) {
     let mut row_edge_list = vec![];
@@ -49,7 +52,30 @@ fn detect_corner(image: &Image, threashold: f64) -> Vec<Pos> {
     ret
 }
 
-pub fn solve(problem_id: usize, image: &Image) -> State {
+/// 縦横の辺と角の候補で分割していくビームサーチ
+pub struct BeamSolver;
+
+impl Solver for BeamSolver {
+    fn name(&self) -> &'static str {
+        "beam"
+    }
+
+    fn description(&self) -> &'static str {
+        "beam search over cuts at detected edges and corners"
+    }
+
+    fn supported_kinds(&self) -> &'static [ProblemKind] {
+        &[ProblemKind::Blank]
+    }
+
+    fn solve(&self, problem: &Problem, context: &SolverContext) -> State {
+        solve(problem, &context.deadline)
+    }
+}
+
+pub fn solve(problem: &Problem, deadline: &Deadline) -> State {
+    let image = &problem.image;
+    let cost_model = &problem.cost_model;
     let cum = CumulativeRMSESum::new(image);
 
     let init_state = State::new(image.height, image.width);
@@ -57,21 +83,24 @@ pub fn solve(problem_id: usize, image: &Image) -> State {
     let (row_edge_list, coloumn_edge_list) = detect_edge(image, 30.0);
     let corner_list = detect_corner(image, 30.0);
 
-    let mut buffer_list = [vec![init_state.clone()], vec![]];
+    let mut buffer_list = [vec![(init_state.clone(), IncrementalEvaluator::new(image, &init_state, cost_model))], vec![]];
     const BEAM_WIDTH: usize = 102;
-    assert!(BEAM_WIDTH % 3 == 0);
+    assert!(BEAM_WIDTH.is_multiple_of(3));
 
     let mut best_state = init_state;
-    let mut best_eval = evaluate(image, &best_state);
-    let mut last_updated_turn = 0;
+    let mut best_eval = evaluate(image, &best_state, cost_model);
 
     for turn in 0.. {
+        if deadline.is_expired() {
+            eprintln!("time is up at turn {}", turn);
+            break;
+        }
         eprintln!("start turn {}", turn);
 
         let mut diff_list = vec![];
 
         for state_index in 0..buffer_list[turn % 2].len() {
-            let state = &buffer_list[turn % 2][state_index];
+            let (state, _) = &buffer_list[turn % 2][state_index];
 
             for block_index in 0..state.block_list.len() {
                 if !state.block_list[block_index].is_child {
@@ -126,7 +155,7 @@ pub fn solve(problem_id: usize, image: &Image) -> State {
             break;
         }
 
-        diff_list.sort_by_key(|(v, _, _)| (-*v as i64));
+        diff_list.sort_by_key(|(v, _, _)| -*v as i64);
 
         // FIXME: 高速化
         buffer_list[(turn + 1) % 2].clear();
@@ -145,10 +174,13 @@ pub fn solve(problem_id: usize, image: &Image) -> State {
             if BEAM_WIDTH / 3 <= command_counter[counter_index] {
                 continue;
             }
-            command_counter[counter_index] += 1;
 
-            let mut state = buffer_list[turn % 2][state_index].clone();
-            state.apply(command);
+            let (mut state, mut evaluator) = buffer_list[turn % 2][state_index].clone();
+            if state.check(&command).is_err() {
+                continue;
+            }
+            evaluator.apply(&mut state, command);
+            command_counter[counter_index] += 1;
 
             let rect = state.block_list[command.block_index()].rect;
             match command {
@@ -156,15 +188,15 @@ pub fn solve(problem_id: usize, image: &Image) -> State {
                     let new_block_index = state.block_list.len() - 2;
                     let bottom_color = cum.mean_color(rect.bottom(), rect.left(), y, rect.right() + 1);
                     let top_color = cum.mean_color(y, rect.left(), rect.top() + 1, rect.right() + 1);
-                    state.apply(Command::Color(new_block_index, bottom_color));
-                    state.apply(Command::Color(new_block_index + 1, top_color));
+                    evaluator.apply(&mut state, Command::Color(new_block_index, bottom_color));
+                    evaluator.apply(&mut state, Command::Color(new_block_index + 1, top_color));
                 }
                 Command::VerticalSplit(_, x) => {
                     let new_block_index = state.block_list.len() - 2;
                     let left_color = cum.mean_color(rect.bottom(), rect.left(), rect.top() + 1, x);
                     let right_color = cum.mean_color(rect.bottom(), x, rect.top() + 1, rect.right() + 1);
-                    state.apply(Command::Color(new_block_index, left_color));
-                    state.apply(Command::Color(new_block_index + 1, right_color));
+                    evaluator.apply(&mut state, Command::Color(new_block_index, left_color));
+                    evaluator.apply(&mut state, Command::Color(new_block_index + 1, right_color));
                 }
                 Command::PointSplit(_, pos) => {
                     let new_block_index = state.block_list.len() - 4;
@@ -173,7 +205,7 @@ pub fn solve(problem_id: usize, image: &Image) -> State {
                     let tr_color = cum.mean_color(pos.y, pos.x, rect.top() + 1, rect.right() + 1);
                     let tl_color = cum.mean_color(pos.y, rect.left(), rect.top() + 1, pos.x);
                     for (index, color) in [bl_color, br_color, tr_color, tl_color].iter().enumerate() {
-                        state.apply(Command::Color(new_block_index + index, *color));
+                        evaluator.apply(&mut state, Command::Color(new_block_index + index, *color));
                     }
                 }
                 _ => {
@@ -181,22 +213,17 @@ pub fn solve(problem_id: usize, image: &Image) -> State {
                 }
             };
 
-            let exact_eval = evaluate(image, &state);
+            let exact_eval = evaluator.score();
             if best_eval > exact_eval {
                 best_eval = exact_eval;
                 best_state = state.clone();
-                last_updated_turn = 0;
             }
-            buffer_list[(turn + 1) % 2].push(state);
+            buffer_list[(turn + 1) % 2].push((state, evaluator));
 
             if buffer_list[(turn + 1) % 2].len() == BEAM_WIDTH {
                 break;
             }
         }
-        last_updated_turn += 1;
-        if last_updated_turn == 20 {
-            break;
-        }
     }
     best_state
 }
@@ -0,0 +1,171 @@
+use crate::{
+    solver::{ProblemKind, Solver, SolverContext},
+    solver2,
+};
+use common::{deadline::Deadline, problem::*, problem_loader::Problem};
+
+/// 既存の解の分割位置を山登りで調整する
+pub struct CutClimbSolver;
+
+impl Solver for CutClimbSolver {
+    fn name(&self) -> &'static str {
+        "cut-climb"
+    }
+
+    fn description(&self) -> &'static str {
+        "hill climbing on the cut positions of the stored best solution"
+    }
+
+    fn supported_kinds(&self) -> &'static [ProblemKind] {
+        &[ProblemKind::Blank, ProblemKind::Twin]
+    }
+
+    fn solve(&self, problem: &Problem, context: &SolverContext) -> State {
+        solve(problem, context.kind, &context.deadline)
+    }
+}
+
+/// 保存済みの最良解から始める. なければ Blank は solver2 の解から、Twin は初期状態から
+/// 初期ブロックの数が問題の初期状態と違う保存済みの解は使わない
+pub fn solve(problem: &Problem, kind: ProblemKind, deadline: &Deadline) -> State {
+    let initial_block_count = problem.initial_state().initial_block_count();
+    let stored_state = StateWithScore::load(problem.id).map(|v| v.state).filter(|state| {
+        let matched = state.initial_block_count() == initial_block_count;
+        if !matched {
+            eprintln!("ignore the stored solution of problem {}: it starts from {} blocks, not {}", problem.id, state.initial_block_count(), initial_block_count);
+        }
+        matched
+    });
+    let init_state = match (stored_state, kind) {
+        (Some(state), _) => state,
+        (None, ProblemKind::Blank) => solver2::solve(problem, deadline),
+        (None, ProblemKind::Twin) => problem.initial_state(),
+    };
+    polish(problem, init_state, deadline)
+}
+
+/// 分割位置を1つ動かした命令. axis は 0 なら y, 1 なら x
+fn moved_command(cmd: Command, axis: usize, delta: i64) -> Option<Command> {
+    let add = |v: usize| -> Option<usize> { v.checked_add_signed(delta as isize).filter(|v| *v > 0) };
+    match (cmd, axis) {
+        (Command::HorizontalSplit(block_index, y), 0) => Some(Command::HorizontalSplit(block_index, add(y)?)),
+        (Command::VerticalSplit(block_index, x), 1) => Some(Command::VerticalSplit(block_index, add(x)?)),
+        (Command::PointSplit(block_index, pos), 0) => Some(Command::PointSplit(block_index, Pos::new(add(pos.y)?, pos.x))),
+        (Command::PointSplit(block_index, pos), 1) => Some(Command::PointSplit(block_index, Pos::new(pos.y, add(pos.x)?))),
+        _ => None,
+    }
+}
+
+/// command_list を再生する. 途中で実行できない命令があれば None
+/// recolor なら、moved_index の分割でできたブロック (と、その子孫) の color を塗る時点のブロックの平均色にする
+fn replay(init_state: &State, image: &Image, command_list: &[Command], moved_index: usize, recolor: bool) -> Option<State> {
+    let mut state = init_state.clone();
+    let mut affected = vec![false; state.block_list.len()];
+    for (i, cmd) in command_list.iter().enumerate() {
+        let cmd = match *cmd {
+            Command::Color(block_index, _) if recolor && affected.get(block_index) == Some(&true) => {
+                Command::Color(block_index, image.mean_color(&state.block_list[block_index].rect))
+            }
+            cmd => cmd,
+        };
+        state.check(&cmd).ok()?;
+        let is_affected = i == moved_index
+            || match cmd {
+                Command::HorizontalSplit(block_index, _) | Command::VerticalSplit(block_index, _) | Command::PointSplit(block_index, _) => affected[block_index],
+                Command::Merge(block_index1, block_index2) => affected[block_index1] || affected[block_index2],
+                _ => false,
+            };
+        state.apply(cmd);
+        // 新しくできたブロック
+        affected.resize(state.block_list.len(), is_affected);
+    }
+    Some(state)
+}
+
+/// 任意の解の分割位置を ±step ずつ動かし、良くなれば採用する
+/// 動かした分割の子孫の色は、元の色のままと平均色に塗り直したものの両方を試す
+/// 途中の解は保存しないので、結果の保存は呼び出し側で行う
+pub fn polish(problem: &Problem, state: State, deadline: &Deadline) -> State {
+    let init_state = state.initial_state();
+    let mut command_list = state.get_command_list();
+    let mut best_score = total_cost(&problem.image, &state, &problem.cost_model);
+    let mut best_state = state;
+
+    for step in [8, 4, 2, 1] {
+        loop {
+            let mut improved = false;
+            for i in 0..command_list.len() {
+                for axis in 0..2 {
+                    for delta in [-step, step] {
+                        if deadline.is_expired() {
+                            return best_state;
+                        }
+                        let Some(cmd) = moved_command(command_list[i], axis, delta) else {
+                            continue;
+                        };
+                        let mut candidate = command_list.clone();
+                        candidate[i] = cmd;
+
+                        for recolor in [false, true] {
+                            let Some(state) = replay(&init_state, &problem.image, &candidate, i, recolor) else {
+                                break;
+                            };
+                            let score = total_cost(&problem.image, &state, &problem.cost_model);
+                            if score < best_score {
+                                eprintln!("update! {} -> {} (step {})", best_score, score, step);
+                                best_score = score;
+                                command_list = state.get_command_list();
+                                best_state = state;
+                                improved = true;
+                            }
+                        }
+                    }
+                }
+            }
+            if !improved {
+                break;
+            }
+        }
+    }
+    best_state
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_polish() {
+        // 左 150 列が赤、残りが青の画像に対して、200 で分割した解
+        let red = Color8::new(255, 0, 0, 255);
+        let blue = Color8::new(0, 0, 255, 255);
+        let mut buffer = vec![blue; 400 * 400];
+        for y in 0..400 {
+            for x in 0..150 {
+                buffer[y * 400 + x] = red;
+            }
+        }
+        let problem = Problem {
+            id: 0,
+            image: Image { height: 400, width: 400, buffer },
+            config: None,
+            initial_image: None,
+            cost_model: CostModel::default(),
+        };
+
+        let mut state = State::new(400, 400);
+        state.apply(Command::VerticalSplit(0, 200));
+        state.apply(Command::Color(1, red));
+        state.apply(Command::Color(2, blue));
+
+        let deadline = Deadline::unlimited();
+        let init_state = state.initial_state();
+        let moved = moved_command(Command::VerticalSplit(0, 200), 1, -400);
+        assert!(moved.is_none());
+        assert!(replay(&init_state, &problem.image, &[Command::VerticalSplit(0, 500)], 0, false).is_none());
+
+        let polished = polish(&problem, state, &deadline);
+        assert_eq!(polished.get_command_list()[0], Command::VerticalSplit(0, 150));
+        assert_eq!(similarity_cost(&problem.image, &polished), 0.0);
+    }
+}
@@ -0,0 +1,587 @@
+use std::{collections::HashMap, time::Instant};
+
+use crate::{
+    solver::{ProblemKind, Solver, SolverContext},
+    solver2,
+};
+use common::{deadline::Deadline, problem::*, problem_loader::Problem, random::CachedRandom};
+
+/// 分割と色の木を焼きなます
+pub struct AnnealingSolver;
+
+impl Solver for AnnealingSolver {
+    fn name(&self) -> &'static str {
+        "anneal"
+    }
+
+    fn description(&self) -> &'static str {
+        "simulated annealing on the cut tree of the stored best solution"
+    }
+
+    fn supported_kinds(&self) -> &'static [ProblemKind] {
+        &[ProblemKind::Blank]
+    }
+
+    fn solve(&self, problem: &Problem, context: &SolverContext) -> State {
+        let init_state = if let Some(v) = StateWithScore::load(problem.id) {
+            v.state
+        } else {
+            solver2::solve(problem, &context.deadline)
+        };
+        anneal(problem, &init_state, &AnnealingConfig::default(), &context.deadline)
+    }
+}
+
+/// 温度の下げ方. 進捗 t (0..1) で start から end まで下げる
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Schedule {
+    Linear,
+    Exponential,
+}
+
+impl Schedule {
+    pub fn parse(name: &str) -> Option<Schedule> {
+        match name {
+            "linear" => Some(Schedule::Linear),
+            "exponential" => Some(Schedule::Exponential),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct AnnealingConfig {
+    pub start_temperature: f64,
+    pub end_temperature: f64,
+    pub schedule: Schedule,
+    // 時間制限がないときの反復回数
+    pub max_iteration: usize,
+    // 分割位置を一度に動かす最大幅
+    pub max_shift: usize,
+    // 色の成分を一度に変える最大幅
+    pub max_color_step: usize,
+    pub seed: u64,
+}
+
+impl Default for AnnealingConfig {
+    fn default() -> Self {
+        AnnealingConfig {
+            start_temperature: 5.0,
+            end_temperature: 0.1,
+            schedule: Schedule::Exponential,
+            max_iteration: 1_000_000,
+            max_shift: 16,
+            max_color_step: 8,
+            seed: 0,
+        }
+    }
+}
+
+impl AnnealingConfig {
+    pub fn temperature(&self, progress: f64) -> f64 {
+        let progress = progress.clamp(0.0, 1.0);
+        match self.schedule {
+            Schedule::Linear => self.start_temperature + (self.end_temperature - self.start_temperature) * progress,
+            Schedule::Exponential => self.start_temperature.powf(1.0 - progress) * self.end_temperature.powf(progress),
+        }
+    }
+
+    /// history に残す設定
+    pub fn params(&self) -> String {
+        format!(
+            "start_temp={} end_temp={} schedule={:?} max_shift={} max_color_step={} seed={}",
+            self.start_temperature, self.end_temperature, self.schedule, self.max_shift, self.max_color_step, self.seed
+        )
+    }
+}
+
+/// 分割位置. ブロックの左下からの相対位置で持つので、部分木を入れ替えてもそのまま使える
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Cut {
+    Horizontal(usize),
+    Vertical(usize),
+    // (y, x)
+    Point(usize, usize),
+}
+
+impl Cut {
+    fn is_valid(&self, rect: &Rectangle) -> bool {
+        let inside = |v: usize, size: usize| 1 <= v && v + 2 <= size;
+        match *self {
+            Cut::Horizontal(y) => inside(y, rect.height),
+            Cut::Vertical(x) => inside(x, rect.width),
+            Cut::Point(y, x) => inside(y, rect.height) && inside(x, rect.width),
+        }
+    }
+
+    /// 子ブロックの範囲. 順番は State の分割と同じ
+    fn child_rects(&self, rect: &Rectangle) -> Vec<Rectangle> {
+        let (b, l, h, w) = (rect.bottom(), rect.left(), rect.height, rect.width);
+        match *self {
+            Cut::Horizontal(y) => vec![Rectangle::new(b, l, y, w), Rectangle::new(b + y, l, h - y, w)],
+            Cut::Vertical(x) => vec![Rectangle::new(b, l, h, x), Rectangle::new(b, l + x, h, w - x)],
+            Cut::Point(y, x) => vec![
+                Rectangle::new(b, l, y, x),
+                Rectangle::new(b, l + x, y, w - x),
+                Rectangle::new(b + y, l + x, h - y, w - x),
+                Rectangle::new(b + y, l, h - y, x),
+            ],
+        }
+    }
+
+    fn to_command(self, block_index: usize, rect: &Rectangle) -> Command {
+        match self {
+            Cut::Horizontal(y) => Command::HorizontalSplit(block_index, rect.bottom() + y),
+            Cut::Vertical(x) => Command::VerticalSplit(block_index, rect.left() + x),
+            Cut::Point(y, x) => Command::PointSplit(block_index, Pos::new(rect.bottom() + y, rect.left() + x)),
+        }
+    }
+
+    fn from_command(cmd: &Command, rect: &Rectangle) -> Option<Cut> {
+        match *cmd {
+            Command::HorizontalSplit(_, y) => Some(Cut::Horizontal(y - rect.bottom())),
+            Command::VerticalSplit(_, x) => Some(Cut::Vertical(x - rect.left())),
+            Command::PointSplit(_, pos) => Some(Cut::Point(pos.y - rect.bottom(), pos.x - rect.left())),
+            _ => None,
+        }
+    }
+}
+
+/// color は分割の前に塗る色. 分割した子はこの色を引き継ぐ
+#[derive(Clone, PartialEq, Debug)]
+struct Node {
+    color: Option<Color8>,
+    split: Option<(Cut, Vec<Node>)>,
+}
+
+impl Node {
+    fn leaf(color: Color8) -> Node {
+        Node { color: Some(color), split: None }
+    }
+
+    fn count(&self) -> usize {
+        match &self.split {
+            None => 1,
+            Some((_, children)) => 1 + children.iter().map(|child| child.count()).sum::<usize>(),
+        }
+    }
+
+    fn is_valid(&self, rect: &Rectangle) -> bool {
+        match &self.split {
+            None => true,
+            Some((cut, children)) => cut.is_valid(rect) && children.iter().zip(cut.child_rects(rect)).all(|(child, rect)| child.is_valid(&rect)),
+        }
+    }
+
+    /// 前順で k 番目のノードとその範囲、親から引き継ぐ色
+    fn nth_mut(&mut self, rect: Rectangle, inherited: Color8, k: &mut usize) -> Option<(&mut Node, Rectangle, Color8)> {
+        if *k == 0 {
+            return Some((self, rect, inherited));
+        }
+        *k -= 1;
+        let color = self.color.unwrap_or(inherited);
+        if let Some((cut, children)) = &mut self.split {
+            let rect_list = cut.child_rects(&rect);
+            for (child, rect) in children.iter_mut().zip(rect_list) {
+                if let Some(ret) = child.nth_mut(rect, color, k) {
+                    return Some(ret);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// 木のコスト. 類似度コストは丸めない
+struct TreeCost<'a> {
+    image: &'a Image,
+    cost_model: &'a CostModel,
+}
+
+impl TreeCost<'_> {
+    fn instruction_cost(&self, base_cost: usize, rect: &Rectangle) -> f64 {
+        (base_cost as f64 * self.image.size() as f64 / rect.size() as f64).round()
+    }
+
+    /// inherited は親から引き継いだ色. 同じ色を塗り直す color は出力しないのでコストもかけない
+    fn cost(&self, node: &Node, rect: &Rectangle, inherited: Color8) -> f64 {
+        let mut cost = 0.0;
+        let color = match node.color {
+            Some(color) if color != inherited => {
+                cost += self.instruction_cost(self.cost_model.color, rect);
+                color
+            }
+            _ => inherited,
+        };
+        match &node.split {
+            None => cost + self.image.rmse(rect, &color) * ALPHA,
+            Some((cut, children)) => {
+                let base_cost = match cut {
+                    Cut::Point(_, _) => self.cost_model.point_cut,
+                    _ => self.cost_model.line_cut,
+                };
+                let child_cost: f64 = children.iter().zip(cut.child_rects(rect)).map(|(child, rect)| self.cost(child, &rect, color)).sum();
+                cost + self.instruction_cost(base_cost, rect) + child_cost
+            }
+        }
+    }
+}
+
+/// state を分割と color だけの木にする. 返り値は (木, 根の block_index)
+/// swap, merge があったり、初期ブロックが複数あったり、初期ブロックが画像のときは None
+fn to_tree(state: &State) -> Option<(Node, usize)> {
+    let mut replayed = state.initial_state();
+    let live_list = (0..replayed.block_list.len()).filter(|i| replayed.block_list[*i].is_child).collect::<Vec<_>>();
+    if live_list.len() != 1 || replayed.block_list[live_list[0]].content != BlockContent::Color {
+        return None;
+    }
+
+    // block_index -> (分割, 最初の子の block_index)
+    let mut split_map = HashMap::new();
+    // block_index -> 最後に塗った色
+    let mut color_map = HashMap::new();
+    for cmd in state.get_command_list() {
+        match cmd {
+            Command::Color(block_index, color) => {
+                color_map.insert(block_index, color);
+            }
+            Command::Swap(_, _) | Command::Merge(_, _) => return None,
+            _ => {
+                let block_index = cmd.block_index();
+                let cut = Cut::from_command(&cmd, &replayed.block_list[block_index].rect)?;
+                split_map.insert(block_index, (cut, replayed.block_list.len()));
+            }
+        }
+        replayed.apply(cmd);
+    }
+
+    fn build(state: &State, split_map: &HashMap<usize, (Cut, usize)>, color_map: &HashMap<usize, Color8>, block_index: usize) -> Node {
+        let split = split_map.get(&block_index).map(|&(cut, child_start)| {
+            let child_count = cut.child_rects(&state.block_list[block_index].rect).len();
+            (cut, (child_start..child_start + child_count).map(|i| build(state, split_map, color_map, i)).collect())
+        });
+        Node {
+            color: color_map.get(&block_index).copied(),
+            split,
+        }
+    }
+    Some((build(&replayed, &split_map, &color_map, live_list[0]), live_list[0]))
+}
+
+/// 木を init_state の root_index のブロックから再生する
+fn to_state(init_state: &State, root_index: usize, tree: &Node) -> State {
+    fn emit(state: &mut State, block_index: usize, node: &Node) {
+        if let Some(color) = node.color {
+            let block = &state.block_list[block_index];
+            if block.color != color || block.content != BlockContent::Color {
+                state.apply(Command::Color(block_index, color));
+            }
+        }
+        if let Some((cut, children)) = &node.split {
+            let child_start = state.block_list.len();
+            state.apply(cut.to_command(block_index, &state.block_list[block_index].rect));
+            for (i, child) in children.iter().enumerate() {
+                emit(state, child_start + i, child);
+            }
+        }
+    }
+    let mut state = init_state.clone();
+    emit(&mut state, root_index, tree);
+    state
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Move {
+    ShiftCut,
+    ChangeCutType,
+    AddCut,
+    RemoveCut,
+    Recolor,
+    SwapChildren,
+}
+
+// 近傍の選ばれやすさ
+const MOVE_WEIGHT_LIST: [(Move, u32); 6] = [
+    (Move::ShiftCut, 30),
+    (Move::ChangeCutType, 5),
+    (Move::AddCut, 15),
+    (Move::RemoveCut, 15),
+    (Move::Recolor, 25),
+    (Move::SwapChildren, 10),
+];
+
+fn select_move(rand: &mut CachedRandom) -> Move {
+    let total: u32 = MOVE_WEIGHT_LIST.iter().map(|v| v.1).sum();
+    let mut r = rand.next_int_range(0, total);
+    for (mv, weight) in MOVE_WEIGHT_LIST {
+        if r < weight {
+            return mv;
+        }
+        r -= weight;
+    }
+    unreachable!()
+}
+
+fn random_cut(rand: &mut CachedRandom, rect: &Rectangle, point: bool) -> Option<Cut> {
+    if point {
+        (rect.height >= 3 && rect.width >= 3).then(|| Cut::Point(rand.next_int_range(1, rect.height as u32 - 1) as usize, rand.next_int_range(1, rect.width as u32 - 1) as usize))
+    } else if rand.next_int_range(0, 2) == 0 {
+        (rect.height >= 3).then(|| Cut::Horizontal(rand.next_int_range(1, rect.height as u32 - 1) as usize))
+    } else {
+        (rect.width >= 3).then(|| Cut::Vertical(rand.next_int_range(1, rect.width as u32 - 1) as usize))
+    }
+}
+
+fn leaf_children(image: &Image, cut: Cut, rect: &Rectangle) -> Vec<Node> {
+    cut.child_rects(rect).iter().map(|rect| Node::leaf(image.mean_color(rect))).collect()
+}
+
+fn shift(rand: &mut CachedRandom, config: &AnnealingConfig, v: usize) -> usize {
+    let delta = rand.next_int_range(1, config.max_shift as u32 + 1) as usize;
+    if rand.next_int_range(0, 2) == 0 {
+        v + delta
+    } else {
+        v.saturating_sub(delta)
+    }
+}
+
+/// node に近傍操作をかける. 適用できなければ false. 結果が範囲外になっていないかは呼び出し側で確認する
+fn apply_move(image: &Image, config: &AnnealingConfig, rand: &mut CachedRandom, node: &mut Node, rect: &Rectangle, inherited: Color8, mv: Move) -> bool {
+    match mv {
+        Move::ShiftCut => {
+            let Some((cut, _)) = &mut node.split else {
+                return false;
+            };
+            *cut = match *cut {
+                Cut::Horizontal(y) => Cut::Horizontal(shift(rand, config, y)),
+                Cut::Vertical(x) => Cut::Vertical(shift(rand, config, x)),
+                Cut::Point(y, x) => match rand.next_int_range(0, 3) {
+                    0 => Cut::Point(shift(rand, config, y), x),
+                    1 => Cut::Point(y, shift(rand, config, x)),
+                    _ => Cut::Point(shift(rand, config, y), shift(rand, config, x)),
+                },
+            };
+        }
+        Move::ChangeCutType => {
+            let Some((cut, _)) = &mut node.split else {
+                return false;
+            };
+            match *cut {
+                // 縦横の入れ替えは、子をそのまま残す
+                Cut::Horizontal(y) if rand.next_int_range(0, 2) == 0 => *cut = Cut::Vertical(y * rect.width / rect.height),
+                Cut::Vertical(x) if rand.next_int_range(0, 2) == 0 => *cut = Cut::Horizontal(x * rect.height / rect.width),
+                current => {
+                    let Some(new_cut) = random_cut(rand, rect, !matches!(current, Cut::Point(_, _))) else {
+                        return false;
+                    };
+                    node.split = Some((new_cut, leaf_children(image, new_cut, rect)));
+                }
+            }
+        }
+        Move::AddCut => {
+            if node.split.is_some() {
+                return false;
+            }
+            let is_point = rand.next_int_range(0, 4) == 0;
+            let Some(cut) = random_cut(rand, rect, is_point) else {
+                return false;
+            };
+            node.split = Some((cut, leaf_children(image, cut, rect)));
+        }
+        Move::RemoveCut => {
+            let Some((_, children)) = &node.split else {
+                return false;
+            };
+            if children.iter().any(|child| child.split.is_some()) {
+                return false;
+            }
+            *node = Node::leaf(image.mean_color(rect));
+        }
+        // 平均色にする、塗るのをやめて親の色を使う、成分を少しずらす のいずれか
+        Move::Recolor => match rand.next_int_range(0, 4) {
+            0 => node.color = Some(image.mean_color(rect)),
+            1 => {
+                if node.color.is_none() {
+                    return false;
+                }
+                node.color = None;
+            }
+            _ => {
+                let mut color = node.color.unwrap_or(inherited);
+                let delta = rand.next_int_range(1, config.max_color_step as u32 + 1) as i32;
+                let delta = if rand.next_int_range(0, 2) == 0 { delta } else { -delta };
+                let channel = match rand.next_int_range(0, 4) {
+                    0 => &mut color.r,
+                    1 => &mut color.g,
+                    2 => &mut color.b,
+                    _ => &mut color.a,
+                };
+                *channel = (*channel as i32 + delta).clamp(0, 255) as u8;
+                node.color = Some(color);
+            }
+        },
+        // 子の並びを反転し、各子の大きさが変わらないように分割位置も反転する
+        Move::SwapChildren => {
+            let Some((cut, children)) = &mut node.split else {
+                return false;
+            };
+            match *cut {
+                Cut::Horizontal(y) => {
+                    *cut = Cut::Horizontal(rect.height - y);
+                    children.swap(0, 1);
+                }
+                Cut::Vertical(x) => {
+                    *cut = Cut::Vertical(rect.width - x);
+                    children.swap(0, 1);
+                }
+                Cut::Point(y, x) => {
+                    if rand.next_int_range(0, 2) == 0 {
+                        *cut = Cut::Point(y, rect.width - x);
+                        children.swap(0, 1);
+                        children.swap(2, 3);
+                    } else {
+                        *cut = Cut::Point(rect.height - y, x);
+                        children.swap(0, 3);
+                        children.swap(1, 2);
+                    }
+                }
+            }
+        }
+    }
+    true
+}
+
+/// state を木にして焼きなます. 木にできない state なら何もせずにそのまま返す
+/// deadline に制限があれば時間で、なければ config.max_iteration で温度を下げる
+pub fn anneal(problem: &Problem, state: &State, config: &AnnealingConfig, deadline: &Deadline) -> State {
+    let init_state = state.initial_state();
+    let Some((mut tree, root_index)) = to_tree(state) else {
+        eprintln!("anneal: the state cannot be a cut tree (swap, merge, several initial blocks or an initial image). skip annealing");
+        return state.clone();
+    };
+    let root_rect = init_state.block_list[root_index].rect;
+    let root_color = init_state.block_list[root_index].color;
+    let tree_cost = TreeCost {
+        image: &problem.image,
+        cost_model: &problem.cost_model,
+    };
+
+    let mut rand = CachedRandom::new(65535, config.seed);
+    let mut score = tree_cost.cost(&tree, &root_rect, root_color);
+    eprintln!("anneal: start from {} nodes, score {}", tree.count(), score);
+    let mut best_score = score;
+    let mut best_tree = tree.clone();
+
+    let start = Instant::now();
+    let time_limit = deadline.remaining();
+    let mut temperature = config.start_temperature;
+    let mut iteration = 0;
+    loop {
+        if iteration % 16 == 0 {
+            if deadline.is_expired() {
+                break;
+            }
+            let progress = match time_limit {
+                Some(time_limit) => start.elapsed().as_secs_f64() / time_limit.as_secs_f64(),
+                None => iteration as f64 / config.max_iteration as f64,
+            };
+            if time_limit.is_none() && iteration >= config.max_iteration {
+                break;
+            }
+            temperature = config.temperature(progress);
+        }
+        iteration += 1;
+
+        let mv = select_move(&mut rand);
+        let index = rand.next_int_range(0, tree.count() as u32) as usize;
+        let (node, rect, inherited) = tree.nth_mut(root_rect, root_color, &mut index.clone()).unwrap();
+        let before = node.clone();
+        if !apply_move(&problem.image, config, &mut rand, node, &rect, inherited, mv) {
+            continue;
+        }
+        if !node.is_valid(&rect) {
+            *node = before;
+            continue;
+        }
+        let delta = tree_cost.cost(node, &rect, inherited) - tree_cost.cost(&before, &rect, inherited);
+        // exp(-delta / T) の確率で受理する
+        if delta > -temperature * rand.next_log_float() {
+            *node = before;
+            continue;
+        }
+        score += delta;
+        if score < best_score - 1e-9 {
+            best_score = score;
+            best_tree = tree.clone();
+        }
+    }
+    eprintln!("anneal: {} iterations, best {}", iteration, best_score);
+
+    to_state(&init_state, root_index, &best_tree)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn two_color_problem() -> Problem {
+        // 左 15 列が赤、残りが青の画像
+        let red = Color8::new(255, 0, 0, 255);
+        let blue = Color8::new(0, 0, 255, 255);
+        let mut buffer = vec![blue; 40 * 40];
+        for y in 0..40 {
+            for x in 0..15 {
+                buffer[y * 40 + x] = red;
+            }
+        }
+        Problem {
+            id: 0,
+            image: Image { height: 40, width: 40, buffer },
+            config: None,
+            initial_image: None,
+            cost_model: CostModel::default(),
+        }
+    }
+
+    #[test]
+    fn test_tree_round_trip() {
+        let mut state = State::new(40, 40);
+        state.apply(Command::VerticalSplit(0, 20));
+        state.apply(Command::Color(1, Color8::new(255, 0, 0, 255)));
+        state.apply(Command::PointSplit(2, Pos::new(10, 30)));
+        state.apply(Command::Color(4, Color8::new(0, 0, 255, 255)));
+
+        let (tree, root_index) = to_tree(&state).unwrap();
+        assert_eq!(root_index, 0);
+        assert_eq!(tree.count(), 7);
+        assert!(tree.is_valid(&Rectangle::new(0, 0, 40, 40)));
+        let replayed = to_state(&state.initial_state(), root_index, &tree);
+        assert_eq!(replayed.get_command_list(), state.get_command_list());
+
+        state.apply(Command::Merge(3, 4));
+        assert!(to_tree(&state).is_none());
+    }
+
+    #[test]
+    fn test_anneal() {
+        let problem = two_color_problem();
+        let state = State::new(40, 40);
+        let config = AnnealingConfig {
+            max_iteration: 20000,
+            ..Default::default()
+        };
+        let annealed = anneal(&problem, &state, &config, &Deadline::unlimited());
+        assert!(total_cost(&problem.image, &annealed, &problem.cost_model) < total_cost(&problem.image, &state, &problem.cost_model));
+        assert!(similarity_cost(&problem.image, &annealed) < similarity_cost(&problem.image, &state));
+
+        // 木にできない解はそのまま返す
+        let mut state = State::new(40, 40);
+        state.apply(Command::VerticalSplit(0, 20));
+        state.apply(Command::Merge(1, 2));
+        let annealed = anneal(&problem, &state, &config, &Deadline::unlimited());
+        assert_eq!(annealed.get_command_list(), state.get_command_list());
+
+        assert_eq!(config.temperature(0.0), config.start_temperature);
+        assert!((config.temperature(1.0) - config.end_temperature).abs() < 1e-9);
+    }
+}
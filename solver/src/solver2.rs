@@ -1,21 +1,57 @@
-use crate::common_solver::{calculate_divisor_list, detect_edge, solve_by_divisor};
-use common::problem::*;
+use crate::{
+    common_solver::{calculate_divisor_list, detect_edge, select_lines, solve_by_divisor, solve_by_divisor_with, DivisorOptions, RectCost},
+    solver::{ProblemKind, Solver, SolverContext},
+};
+use common::{deadline::Deadline, problem::*, problem_loader::Problem};
 
-pub fn solve(problem_id: usize, image: &Image) -> State {
+/// 行・列の区切りを決めて DP で分割と色を決める
+pub struct DivisorSolver;
+
+impl Solver for DivisorSolver {
+    fn name(&self) -> &'static str {
+        "divisor"
+    }
+
+    fn description(&self) -> &'static str {
+        "grid division by detected edges / fixed steps, solved by DP"
+    }
+
+    fn supported_kinds(&self) -> &'static [ProblemKind] {
+        &[ProblemKind::Blank]
+    }
+
+    fn solve(&self, problem: &Problem, context: &SolverContext) -> State {
+        solve(problem, &context.deadline)
+    }
+}
+
+pub fn solve(problem: &Problem, deadline: &Deadline) -> State {
+    let problem_id = problem.id;
+    let image = &problem.image;
+    let cost_model = &problem.cost_model;
     const POS_THREASHOLD: usize = 50;
+    const APPROXIMATE_POS_THREASHOLD: usize = 60;
+    // select_lines で選ぶ線の本数
+    const LINE_BUDGET: usize = 30;
 
-    let mut best_state = State::new(image.height, image.width);
-    let mut best_score = evaluate(image, &best_state);
+    let mut best_state = problem.initial_state();
+    let mut best_score = evaluate(image, &best_state, cost_model);
 
     {
         // edge 検出して、パターン数が少なければやってみる
         let (row_list, column_list) = detect_edge(image, 20.0);
-        if row_list.len() <= POS_THREASHOLD && column_list.len() <= POS_THREASHOLD {
-            eprintln!("trying edge based division");
+        let line_count = row_list.len().max(column_list.len());
+        if line_count <= APPROXIMATE_POS_THREASHOLD {
+            // 線が多すぎるときは、色のコストを近似して解く
+            let options = DivisorOptions {
+                rect_cost: if line_count <= POS_THREASHOLD { RectCost::Exact } else { RectCost::Approximate },
+                ..Default::default()
+            };
+            eprintln!("trying edge based division ({:?})", options.rect_cost);
             eprintln!("row {:?}", row_list);
             eprintln!("col {:?}", column_list);
-            let state = solve_by_divisor(image, &row_list, &column_list);
-            let exact_score = evaluate(image, &state);
+            let state = problem.replay_on_initial_state(&solve_by_divisor_with(image, cost_model, &row_list, &column_list, deadline, &options));
+            let exact_score = evaluate(image, &state, cost_model);
             eprintln!("update: {} -> {}", best_score, exact_score);
 
             if best_score > exact_score {
@@ -33,12 +69,37 @@ pub fn solve(problem_id: usize, image: &Image) -> State {
         }
     }
 
+    if !deadline.is_expired() {
+        // 二乗誤差が大きく減る線を選んで試す
+        let (row_list, column_list) = select_lines(image, LINE_BUDGET);
+        eprintln!("trying selected lines");
+        eprintln!("row {:?}", row_list);
+        eprintln!("col {:?}", column_list);
+        let state = problem.replay_on_initial_state(&solve_by_divisor(image, cost_model, &row_list, &column_list, deadline));
+        let exact_score = evaluate(image, &state, cost_model);
+        eprintln!("update: {} -> {}", best_score, exact_score);
+
+        if best_score > exact_score {
+            best_score = exact_score;
+            best_state = state;
+
+            StateWithScore {
+                score: best_score,
+                state: best_state.clone(),
+            }
+            .save_if_global_best(problem_id);
+        }
+    }
+
     {
         let image_size = 400;
         let step_list = calculate_divisor_list(image_size);
         eprintln!("step_list: {:?}", step_list);
 
         for step in step_list.into_iter() {
+            if deadline.is_expired() {
+                break;
+            }
             if image_size > POS_THREASHOLD * step {
                 eprintln!("skip because step is too small ... {}", step);
                 continue;
@@ -53,8 +114,8 @@ pub fn solve(problem_id: usize, image: &Image) -> State {
                 row_list.push(i);
             }
 
-            let state = solve_by_divisor(image, &row_list, &column_list);
-            let exact_score = evaluate(image, &state);
+            let state = problem.replay_on_initial_state(&solve_by_divisor(image, cost_model, &row_list, &column_list, deadline));
+            let exact_score = evaluate(image, &state, cost_model);
             eprintln!("update: {} -> {}", best_score, exact_score);
             if best_score > exact_score {
                 best_score = exact_score;
@@ -1,24 +1,47 @@
-use common::{cumulative_sum::RangeColorMedianCalculator, problem::*};
-use std::path::Path;
+use common::{cumulative_sum::RangeColorMedianCalculator, deadline::Deadline, problem::*, problem_loader::Problem};
 
-pub fn solve(problem_id: usize, image: &Image) -> State {
-    let str_path = format!("dataset/{}.initial.json", problem_id);
-    let config_path = Path::new(&str_path);
-    let config = common::config_loader::TwinImageConfig::load(config_path);
+use crate::solver::{ProblemKind, Solver, SolverContext};
 
-    let mut state = State::create_with_config(&config);
+/// 初期ブロックをそれぞれ median の色で塗る
+pub struct TwinMedianSolver;
+
+impl Solver for TwinMedianSolver {
+    fn name(&self) -> &'static str {
+        "twin-median"
+    }
+
+    fn description(&self) -> &'static str {
+        "paint each initial block with its median color"
+    }
+
+    fn supported_kinds(&self) -> &'static [ProblemKind] {
+        &[ProblemKind::Twin]
+    }
+
+    fn solve(&self, problem: &Problem, context: &SolverContext) -> State {
+        solve(problem, &context.deadline)
+    }
+}
+
+pub fn solve(problem: &Problem, deadline: &Deadline) -> State {
+    let image = &problem.image;
+    let cost_model = &problem.cost_model;
+    let mut state = problem.initial_state();
 
     let median_calculator = RangeColorMedianCalculator::new(image);
 
-    let mut best_eval = evaluate(image, &state);
+    let mut best_eval = evaluate(image, &state, cost_model);
 
     for block_index in 0..state.block_list.len() {
+        if deadline.is_expired() {
+            break;
+        }
         if state.block_list[block_index].is_child {
             // median の色を塗る
             let rect = state.block_list[block_index].rect;
             let color = median_calculator.median(rect.bottom(), rect.left(), rect.top(), rect.right());
             state.apply(Command::Color(block_index, color));
-            let eval = evaluate(image, &state);
+            let eval = evaluate(image, &state, cost_model);
             if best_eval > eval {
                 best_eval = eval;
             } else {
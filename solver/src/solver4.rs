@@ -1,12 +1,32 @@
-use common::{cumulative_sum::RangeColorMedianCalculator, problem::*};
-use std::path::Path;
+use common::{cumulative_sum::RangeColorMedianCalculator, deadline::Deadline, problem::*, problem_loader::Problem};
 
-pub fn solve(problem_id: usize, image: &Image) -> State {
-    let str_path = format!("dataset/{}.initial.json", problem_id);
-    let config_path = Path::new(&str_path);
-    let config = common::config_loader::TwinImageConfig::load(config_path);
+use crate::solver::{ProblemKind, Solver, SolverContext};
 
-    let state = State::create_with_config(&config);
+/// 同じ色の初期ブロックを縦か横に merge してから塗る
+pub struct TwinMergeLineSolver;
+
+impl Solver for TwinMergeLineSolver {
+    fn name(&self) -> &'static str {
+        "twin-merge-line"
+    }
+
+    fn description(&self) -> &'static str {
+        "merge same-colored initial blocks along rows or columns, then paint"
+    }
+
+    fn supported_kinds(&self) -> &'static [ProblemKind] {
+        &[ProblemKind::Twin]
+    }
+
+    fn solve(&self, problem: &Problem, context: &SolverContext) -> State {
+        solve(problem, &context.deadline)
+    }
+}
+
+pub fn solve(problem: &Problem, _deadline: &Deadline) -> State {
+    let image = &problem.image;
+    let cost_model = &problem.cost_model;
+    let state = problem.initial_state();
 
     let median_calculator = RangeColorMedianCalculator::new(image);
 
@@ -66,8 +86,8 @@ pub fn solve(problem_id: usize, image: &Image) -> State {
         }
     }
 
-    let cost_x = evaluate(image, &state_x);
-    let cost_y = evaluate(image, &state_y);
+    let cost_x = evaluate(image, &state_x, cost_model);
+    let cost_y = evaluate(image, &state_y, cost_model);
     if cost_x < cost_y {
         state_x
     } else {
@@ -1,17 +1,39 @@
-use std::{path::Path, time::Instant};
+use crate::{
+    common_solver,
+    solver::{ProblemKind, Solver, SolverContext},
+    solver2,
+};
+use common::{deadline::Deadline, intset::IntSet, problem::*, problem_loader::Problem, random::CachedRandom};
+
+/// divisor の行・列を山登りで調整する
+pub struct DivisorClimbSolver;
+
+impl Solver for DivisorClimbSolver {
+    fn name(&self) -> &'static str {
+        "divisor-climb"
+    }
 
-use crate::{common_solver, solver2};
-use common::{intset::IntSet, problem::*, random::CachedRandom};
+    fn description(&self) -> &'static str {
+        "hill climbing on the row / column division of divisor"
+    }
+
+    fn supported_kinds(&self) -> &'static [ProblemKind] {
+        &[ProblemKind::Blank]
+    }
+
+    fn solve(&self, problem: &Problem, context: &SolverContext) -> State {
+        solve(problem, &context.deadline)
+    }
+}
 
 /// row や column を ±1 して調整する山登り
 /// 本当は敷居を増やすのも効果はかなりある（このルールだと損しないので）けど、
 /// 時間を見てかなー
-pub fn solve(problem_id: usize, image: &Image) -> State {
-    let init_state = if let Some(v) = StateWithScore::load(problem_id) {
-        v.state
-    } else {
-        solver2::solve(problem_id, image)
-    };
+pub fn solve(problem: &Problem, deadline: &Deadline) -> State {
+    let problem_id = problem.id;
+    let image = &problem.image;
+    let cost_model = &problem.cost_model;
+    let init_state = if let Some(v) = StateWithScore::load(problem_id) { v.state } else { solver2::solve(problem, deadline) };
 
     let mut row_list = vec![];
     let mut column_list = vec![];
@@ -37,7 +59,7 @@ pub fn solve(problem_id: usize, image: &Image) -> State {
     row_list.sort();
     column_list.sort();
 
-    let mut best_eval = evaluate(image, &init_state);
+    let mut best_eval = evaluate(image, &init_state, cost_model);
     let mut best_state = init_state;
 
     let mut rand = CachedRandom::new(65535, 0);
@@ -53,13 +75,17 @@ pub fn solve(problem_id: usize, image: &Image) -> State {
 
         let mut turn = 0;
         while !dlb.is_empty() {
+            if deadline.is_expired() {
+                finish = true;
+                break;
+            }
             eprintln!("start turn {}, dlb size: {}", turn, dlb.size());
             turn += 1;
 
             let root_index = dlb.choose(&mut rand);
 
             let select_column = root_index < column_list.len() - 2;
-            let index = if select_column { root_index + 1 } else { root_index - (column_list.len() - 2) + 1 } as usize;
+            let index = if select_column { root_index + 1 } else { root_index - (column_list.len() - 2) + 1 };
 
             let mut success = false;
             for offset in [-1, 1].into_iter() {
@@ -81,14 +107,9 @@ pub fn solve(problem_id: usize, image: &Image) -> State {
                     row_list[index] = (row_list[index] as i64 + offset) as usize;
                 }
 
-                let start = Instant::now();
-                let state = common_solver::solve_by_divisor(image, &row_list, &column_list);
-                let elapsed = (Instant::now() - start).as_secs();
-                if elapsed > 20 {
-                    finish = true;
-                }
+                let state = problem.replay_on_initial_state(&common_solver::solve_by_divisor(image, cost_model, &row_list, &column_list, deadline));
 
-                let eval = evaluate(image, &state);
+                let eval = evaluate(image, &state, cost_model);
 
                 if best_eval > eval {
                     success = true;
@@ -1,8 +1,43 @@
-use common::{cumulative_sum::RangeColorMedianCalculator, problem::*};
+use common::{cumulative_sum::RangeColorMedianCalculator, deadline::Deadline, problem::*, problem_loader::Problem};
 
-use crate::common_solver::{calculate_divisor_list, detect_edge};
+use crate::{
+    common_solver::detect_edge,
+    solver::{ProblemKind, Solver, SolverContext},
+};
 
-pub fn solve(problem_id: usize, image: &Image) -> State {
+/// 問題 1 専用
+pub struct Problem1Solver;
+
+impl Solver for Problem1Solver {
+    fn name(&self) -> &'static str {
+        "problem1"
+    }
+
+    fn description(&self) -> &'static str {
+        "hand-made solution for problem 1 only"
+    }
+
+    fn supported_kinds(&self) -> &'static [ProblemKind] {
+        &[ProblemKind::Blank]
+    }
+
+    fn check(&self, problem: &Problem, context: &SolverContext) -> Result<(), String> {
+        if problem.id != 1 {
+            return Err(format!("solver '{}' is only for problem 1", self.name()));
+        }
+        if context.kind != ProblemKind::Blank {
+            return Err(format!("solver '{}' does not support {} problems", self.name(), context.kind.name()));
+        }
+        Ok(())
+    }
+
+    fn solve(&self, problem: &Problem, context: &SolverContext) -> State {
+        solve(problem, &context.deadline)
+    }
+}
+
+pub fn solve(problem: &Problem, _deadline: &Deadline) -> State {
+    let image = &problem.image;
     let median_calculator = RangeColorMedianCalculator::new(image);
 
     let (row_list, mut column_list) = detect_edge(image, 30.0);
@@ -24,7 +59,7 @@ pub fn solve(problem_id: usize, image: &Image) -> State {
     state.apply(Command::Color(4, blue_color));
 
     // 横1列の block の index
-    let mut single_block_index_table = vec![0; 10];
+    let mut single_block_index_table = [0; 10];
     {
         let mut split_target_block_id = 3;
         for yi in 2..10 {
@@ -47,7 +82,7 @@ pub fn solve(problem_id: usize, image: &Image) -> State {
             dual_block_index_table[yi][1] = state.block_list.len() - 1;
         }
     }
-    let mut single_column_block_index_table = vec![0; 10];
+    let mut single_column_block_index_table = [0; 10];
 
     // 左列 merge
     {
@@ -1,7 +1,8 @@
-use std::time::Instant;
-
-use crate::common_solver::solve_by_divisor;
-use common::{clustering::k_means_clustering, problem::*, random::CachedRandom};
+use crate::{
+    common_solver::solve_by_divisor,
+    solver::{ProblemKind, Solver, SolverContext},
+};
+use common::{clustering::k_means_clustering, deadline::Deadline, problem::*, problem_loader::Problem, random::CachedRandom};
 
 // row_list, column_list
 fn extract_edge_from_rect(rect_list: &Vec<Rectangle>, height: usize, width: usize) -> (Vec<usize>, Vec<usize>) {
@@ -33,7 +34,31 @@ fn extract_edge_from_rect(rect_list: &Vec<Rectangle>, height: usize, width: usiz
     (row_list, column_list)
 }
 
-pub fn solve(problem_id: usize, image: &Image) -> State {
+/// k-means で色分けした領域から区切りを作る
+pub struct KMeansSolver;
+
+impl Solver for KMeansSolver {
+    fn name(&self) -> &'static str {
+        "kmeans"
+    }
+
+    fn description(&self) -> &'static str {
+        "divisor with rows / columns from k-means color regions"
+    }
+
+    fn supported_kinds(&self) -> &'static [ProblemKind] {
+        &[ProblemKind::Blank]
+    }
+
+    fn solve(&self, problem: &Problem, context: &SolverContext) -> State {
+        solve(problem, &context.deadline)
+    }
+}
+
+pub fn solve(problem: &Problem, deadline: &Deadline) -> State {
+    let problem_id = problem.id;
+    let image = &problem.image;
+    let cost_model = &problem.cost_model;
     // for k-means clustering
     let mut color_buffer = vec![];
     for y in 0..image.height {
@@ -45,15 +70,18 @@ pub fn solve(problem_id: usize, image: &Image) -> State {
 
     let mut random = CachedRandom::new(65536, 42);
 
-    const INVALID_ID: usize = std::usize::MAX;
+    const INVALID_ID: usize = usize::MAX;
 
-    let mut best_state = State::new(image.height, image.width);
-    let mut best_eval = evaluate(image, &best_state);
+    let mut best_state = problem.initial_state();
+    let mut best_eval = evaluate(image, &best_state, cost_model);
 
     for num_color in 2..10 {
+        if deadline.is_expired() {
+            break;
+        }
         // k-means clustering で色分け
         // なければ終了
-        if let Some((assign_table, color_list)) = k_means_clustering(&color_buffer, num_color, &mut random) {
+        if let Some((assign_table, _)) = k_means_clustering(&color_buffer, num_color, &mut random) {
             // 色の代わりに色 id で table 作り直し
             let mut color_number_table = vec![vec![0; image.width]; image.height];
             for y in 0..image.height {
@@ -62,20 +90,6 @@ pub fn solve(problem_id: usize, image: &Image) -> State {
                     color_number_table[y][x] = assign_table[index];
                 }
             }
-            {
-                // debug
-                let mut clone = image.clone();
-                for y in 0..image.height {
-                    for x in 0..image.width {
-                        let from_index = y * image.width + x;
-                        let to_index = (image.height - 1 - y) * image.width + x;
-                        clone.buffer[to_index] = color_list[assign_table[from_index]];
-                    }
-                }
-                let filepath = "intermediate.png".to_string();
-                clone.save_image(&filepath);
-            }
-
             let mut rectangle_buffer = vec![];
 
             loop {
@@ -127,6 +141,10 @@ pub fn solve(problem_id: usize, image: &Image) -> State {
                         }
                     }
                 }
+                // 全部の画素を長方形で覆い終わった
+                if best_size == 0 {
+                    break;
+                }
                 eprintln!("c = {}. {:?}", best_color, best_rect);
 
                 // 長方形の color_number_table を INVALID で上書き
@@ -138,15 +156,13 @@ pub fn solve(problem_id: usize, image: &Image) -> State {
                 }
                 rectangle_buffer.push(best_rect);
 
-                let start_time = Instant::now();
                 let (row_list, column_list) = extract_edge_from_rect(&rectangle_buffer, image.height, image.width);
-                let elapsed = (Instant::now() - start_time).as_secs();
 
                 eprintln!("row_list: {:?}", row_list);
                 eprintln!("column_list: {:?}", column_list);
 
-                let state = solve_by_divisor(image, &row_list, &column_list);
-                let eval = evaluate(image, &state);
+                let state = problem.replay_on_initial_state(&solve_by_divisor(image, cost_model, &row_list, &column_list, deadline));
+                let eval = evaluate(image, &state, cost_model);
 
                 eprintln!("color num: {}, rect_num: {}, eval = {}", num_color, rectangle_buffer.len(), eval);
 
@@ -160,7 +176,7 @@ pub fn solve(problem_id: usize, image: &Image) -> State {
                     .save_if_global_best(problem_id);
                 }
 
-                if elapsed > 20 {
+                if deadline.is_expired() {
                     break;
                 }
             }
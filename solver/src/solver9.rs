@@ -1,18 +1,38 @@
-use common::{intset::IntSet, problem::*, random::CachedRandom};
-use std::{path::Path, time::Instant};
+use common::{deadline::Deadline, intset::IntSet, problem::*, problem_loader::Problem, random::CachedRandom};
 
 use crate::{
-    common_solver::{self, calculate_divisor_list, detect_edge, solve_by_divisor},
+    common_solver,
+    solver::{ProblemKind, Solver, SolverContext},
     solver2,
 };
 
-// 全て merge して、 solver2::solve と同じことをします
-pub fn solve(problem_id: usize, image: &Image) -> State {
-    let str_path = format!("dataset/{}.initial.json", problem_id);
-    let config_path = Path::new(&str_path);
-    let config = common::config_loader::TwinImageConfig::load(config_path);
+/// 全ブロックを merge してから divisor-climb と同じことをする
+pub struct TwinMergeClimbSolver;
+
+impl Solver for TwinMergeClimbSolver {
+    fn name(&self) -> &'static str {
+        "twin-merge-climb"
+    }
+
+    fn description(&self) -> &'static str {
+        "merge all initial blocks, then run divisor-climb"
+    }
 
-    let mut state = State::create_with_config(&config);
+    fn supported_kinds(&self) -> &'static [ProblemKind] {
+        &[ProblemKind::Twin]
+    }
+
+    fn solve(&self, problem: &Problem, context: &SolverContext) -> State {
+        solve(problem, &context.deadline)
+    }
+}
+
+// 全て merge して、 solver2::solve と同じことをします
+pub fn solve(problem: &Problem, deadline: &Deadline) -> State {
+    let problem_id = problem.id;
+    let image = &problem.image;
+    let cost_model = &problem.cost_model;
+    let mut state = problem.initial_state();
 
     let unit = state.block_list.last().unwrap().rect.height;
     let dim = image.height / unit;
@@ -65,11 +85,7 @@ pub fn solve(problem_id: usize, image: &Image) -> State {
     // solver 6 のコピペ
     // コピペしないと、途中の解が invalid になるのでダメでした
 
-    let init_state = if let Some(v) = StateWithScore::load(problem_id) {
-        v.state
-    } else {
-        solver2::solve(problem_id, image)
-    };
+    let init_state = if let Some(v) = StateWithScore::load(problem_id) { v.state } else { solver2::solve(problem, deadline) };
 
     let mut row_list = vec![];
     let mut column_list = vec![];
@@ -95,7 +111,7 @@ pub fn solve(problem_id: usize, image: &Image) -> State {
     row_list.sort();
     column_list.sort();
 
-    let mut best_eval = evaluate(image, &init_state);
+    let mut best_eval = evaluate(image, &init_state, cost_model);
     let mut best_state = init_state;
 
     let mut rand = CachedRandom::new(65535, 0);
@@ -111,13 +127,17 @@ pub fn solve(problem_id: usize, image: &Image) -> State {
 
         let mut turn = 0;
         while !dlb.is_empty() {
+            if deadline.is_expired() {
+                finish = true;
+                break;
+            }
             eprintln!("start turn {}, dlb size: {}", turn, dlb.size());
             turn += 1;
 
             let root_index = dlb.choose(&mut rand);
 
             let select_column = root_index < column_list.len() - 2;
-            let index = if select_column { root_index + 1 } else { root_index - (column_list.len() - 2) + 1 } as usize;
+            let index = if select_column { root_index + 1 } else { root_index - (column_list.len() - 2) + 1 };
 
             let mut success = false;
             for offset in [-1, 1].into_iter() {
@@ -139,16 +159,10 @@ pub fn solve(problem_id: usize, image: &Image) -> State {
                     row_list[index] = (row_list[index] as i64 + offset) as usize;
                 }
 
-                let start = Instant::now();
-                let pre_state = common_solver::solve_by_divisor(image, &row_list, &column_list);
+                let pre_state = common_solver::solve_by_divisor(image, cost_model, &row_list, &column_list, deadline);
                 let state = apply_prestate(pre_state);
 
-                let elapsed = (Instant::now() - start).as_secs();
-                if elapsed > 20 {
-                    finish = true;
-                }
-
-                let eval = evaluate(image, &state);
+                let eval = evaluate(image, &state, cost_model);
 
                 if best_eval > eval {
                     success = true;